@@ -8,7 +8,8 @@ const MOVEMENT_SPEED: f64 = 1.0;
 pub static TETROMINOS: [Tetromino; 7] = [
   Tetromino {
     name: Shape::I,
-    blocks: [[0, 1, 0, 0], [0, 1, 0, 0], [0, 1, 0, 0], [0, 1, 0, 0]],
+    blocks: [[0, 0, 0, 0], [1, 1, 1, 1], [0, 0, 0, 0], [0, 0, 0, 0]],
+    rotation: 0,
     x_offset: (BOARD_WIDTH as f64 / 2.0) - 2.0,
     y_offset: 0.0,
     color: [0.0, 1.0, 1.0, 1.0]
@@ -16,47 +17,88 @@ pub static TETROMINOS: [Tetromino; 7] = [
   Tetromino {
     name: Shape::O,
     blocks: [[1, 1, 0, 0], [1, 1, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]],
+    rotation: 0,
     x_offset: (BOARD_WIDTH as f64 / 2.0) - 1.0,
     y_offset: 0.0,
     color: [1.0, 1.0, 0.0, 1.0]
   },
   Tetromino {
     name: Shape::T,
-    blocks: [[0, 0, 0, 0], [1, 1, 1, 0], [0, 1, 0, 0], [0, 0, 0, 0]],
+    blocks: [[0, 1, 0, 0], [1, 1, 1, 0], [0, 0, 0, 0], [0, 0, 0, 0]],
+    rotation: 0,
     x_offset: (BOARD_WIDTH as f64 / 2.0) - 2.0,
     y_offset: 0.0,
     color: [0.4, 0.0, 0.8, 1.0]
   },
   Tetromino {
     name: Shape::S,
-    blocks: [[0, 0, 0, 0], [0, 1, 1, 0], [1, 1, 0, 0], [0, 0, 0, 0]],
+    blocks: [[0, 1, 1, 0], [1, 1, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]],
+    rotation: 0,
     x_offset: (BOARD_WIDTH as f64 / 2.0) - 2.0,
     y_offset: 0.0,
     color: [0.48, 1.0, 0.0, 1.0]
   },
   Tetromino {
     name: Shape::Z,
-    blocks: [[0, 0, 0, 0], [1, 1, 0, 0], [0, 1, 1, 0], [0, 0, 0, 0]],
+    blocks: [[1, 1, 0, 0], [0, 1, 1, 0], [0, 0, 0, 0], [0, 0, 0, 0]],
+    rotation: 0,
     x_offset: (BOARD_WIDTH as f64 / 2.0) - 2.0,
     y_offset: 0.0,
     color: [1.0, 0.0, 0.0, 1.0]
   },  
   Tetromino {
     name: Shape::J,
-    blocks: [[0, 1, 0, 0], [0, 1, 0, 0], [1, 1, 0, 0], [0, 0, 0, 0]],
+    blocks: [[1, 0, 0, 0], [1, 1, 1, 0], [0, 0, 0, 0], [0, 0, 0, 0]],
+    rotation: 0,
     x_offset: (BOARD_WIDTH as f64 / 2.0) - 2.0,
     y_offset: 0.0,
     color: [0.11, 0.56, 1.0, 1.0]
   },
   Tetromino {
     name: Shape::L,
-    blocks: [[0, 1, 0, 0], [0, 1, 0, 0], [0, 1, 1, 0], [0, 0, 0, 0]],
+    blocks: [[0, 0, 1, 0], [1, 1, 1, 0], [0, 0, 0, 0], [0, 0, 0, 0]],
+    rotation: 0,
     x_offset: (BOARD_WIDTH as f64 / 2.0) - 2.0,
     y_offset: 0.0,
     color: [1.0, 0.6, 0.0, 1.0]
   },
 ];
 
+// SRS wall kicks: offsets to try, in order, when a rotation doesn't fit where it is.
+// these are (x, y) with +y going up, like the tables on the tetris wiki, so y gets
+// flipped before it's applied to the board. indexed by the rotation state we're
+// rotating from (0 = spawn, 1 = R, 2 = 2, 3 = L)
+const JLSTZ_KICKS_CW: [[(i64, i64); 5]; 4] = [
+  [(0, 0), (-1, 0), (-1, 1), (0, -2), (-1, -2)], // 0 -> R
+  [(0, 0), (1, 0), (1, -1), (0, 2), (1, 2)], // R -> 2
+  [(0, 0), (1, 0), (1, 1), (0, -2), (1, -2)], // 2 -> L
+  [(0, 0), (-1, 0), (-1, -1), (0, 2), (-1, 2)] // L -> 0
+];
+
+const JLSTZ_KICKS_CCW: [[(i64, i64); 5]; 4] = [
+  [(0, 0), (1, 0), (1, 1), (0, -2), (1, -2)], // 0 -> L
+  [(0, 0), (1, 0), (1, -1), (0, 2), (1, 2)], // R -> 0
+  [(0, 0), (-1, 0), (-1, 1), (0, -2), (-1, -2)], // 2 -> R
+  [(0, 0), (-1, 0), (-1, -1), (0, 2), (-1, 2)] // L -> 2
+];
+
+const I_KICKS_CW: [[(i64, i64); 5]; 4] = [
+  [(0, 0), (-2, 0), (1, 0), (-2, -1), (1, 2)], // 0 -> R
+  [(0, 0), (-1, 0), (2, 0), (-1, 2), (2, -1)], // R -> 2
+  [(0, 0), (2, 0), (-1, 0), (2, 1), (-1, -2)], // 2 -> L
+  [(0, 0), (1, 0), (-2, 0), (1, -2), (-2, 1)] // L -> 0
+];
+
+const I_KICKS_CCW: [[(i64, i64); 5]; 4] = [
+  [(0, 0), (-1, 0), (2, 0), (-1, 2), (2, -1)], // 0 -> L
+  [(0, 0), (2, 0), (-1, 0), (2, 1), (-1, -2)], // R -> 0
+  [(0, 0), (1, 0), (-2, 0), (1, -2), (-2, 1)], // 2 -> R
+  [(0, 0), (-2, 0), (1, 0), (-2, -1), (1, 2)] // L -> 2
+];
+
+// the O piece doesn't kick
+const NO_KICKS: [(i64, i64); 1] = [(0, 0)];
+
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub enum Shape {
   I,
@@ -68,40 +110,77 @@ pub enum Shape {
   L
 }
 
-#[derive(Debug, Clone, Copy)]
+impl Shape {
+  pub fn color(&self) -> [f32; 4] {
+    Tetromino::spawn(*self).color
+  }
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub struct Tetromino {
   pub name: Shape,
   pub blocks: [[u8; 4]; 4],
+  pub rotation: usize, // SRS rotation state: 0 = spawn, 1 = R, 2 = 2, 3 = L
   pub x_offset: f64, // offset (in block cell units) of the top left cell
   pub y_offset: f64,
   pub color: [f32; 4] // color of the block
 }
 
 impl Tetromino {
+  // fresh copy of a piece in its spawn position and rotation
+  pub fn spawn(shape: Shape) -> Tetromino {
+    *TETROMINOS.iter().find(|t| t.name == shape).unwrap()
+  }
+
+  // pieces rotate inside a 3x3 box in the top left of `blocks`, except for the I (4x4)
+  // and O (doesn't rotate at all)
+  fn box_size(&self) -> usize {
+    match self.name {
+      Shape::I => 4,
+      Shape::O => 2,
+      _ => 3
+    }
+  }
+
   pub fn rotate_right(&mut self) {
     if self.name == Shape::O {
       return
     }
+    let n = self.box_size();
     let mut new_blocks = [[0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]];
-    for r in 0..self.blocks.len() {
-      for c in 0..self.blocks[0].len() {
-        new_blocks[r][c] = self.blocks[4 - c - 1][r];
+    for (r, row) in new_blocks.iter_mut().enumerate().take(n) {
+      for (c, block) in row.iter_mut().enumerate().take(n) {
+        *block = self.blocks[n - c - 1][r];
       }
     }
     self.blocks = new_blocks;
+    self.rotation = (self.rotation + 1) % 4;
   }
 
   pub fn rotate_left(&mut self) {
     if self.name == Shape::O {
       return
     }
+    let n = self.box_size();
     let mut new_blocks = [[0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]];
-    for r in 0..self.blocks.len() {
-      for c in 0..self.blocks[0].len() {
-        new_blocks[r][c] = self.blocks[c][4 - r - 1];
+    for (r, row) in new_blocks.iter_mut().enumerate().take(n) {
+      for (c, block) in row.iter_mut().enumerate().take(n) {
+        *block = self.blocks[c][n - r - 1];
       }
     }
     self.blocks = new_blocks;
+    self.rotation = (self.rotation + 3) % 4;
+  }
+
+  // kick offsets to try when rotating out of the current rotation state
+  pub fn kicks(&self, clockwise: bool) -> &'static [(i64, i64)] {
+    match (self.name, clockwise) {
+      (Shape::O, _) => &NO_KICKS,
+      (Shape::I, true) => &I_KICKS_CW[self.rotation],
+      (Shape::I, false) => &I_KICKS_CCW[self.rotation],
+      (_, true) => &JLSTZ_KICKS_CW[self.rotation],
+      (_, false) => &JLSTZ_KICKS_CCW[self.rotation]
+    }
   }
 
   pub fn block_height(&self) -> f64 {
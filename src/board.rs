@@ -1,18 +1,36 @@
-use piston::input::*;
-
-use rand::Rng;
+use rand::{Rng, SeedableRng, Isaac64Rng};
 
 use super::BOARD_WIDTH;
 use super::BOARD_HEIGHT;
+use super::TICKS_PER_SECOND;
 use super::block::Shape;
 use super::block::Tetromino;
 use super::block::TETROMINOS;
 
-// 1st entry is # of points for clearing 1 line, etc.
-const SCORES_PER_LINE: [u64; 4] = [80, 200, 600, 2400];
+// guideline scoring. all of these get multiplied by the level the clear happened on.
+// 1st entry is # of points for locking without clearing anything, then 1 line, etc.
+const LINE_POINTS: [u64; 5] = [0, 100, 300, 500, 800];
+const MINI_TSPIN_POINTS: [u64; 3] = [100, 200, 400];
+const TSPIN_POINTS: [u64; 4] = [400, 800, 1200, 1600];
+// on top of the clear itself, for leaving the board empty
+const ALL_CLEAR_POINTS: [u64; 4] = [800, 1200, 1800, 2000];
+const COMBO_POINTS: u64 = 50; // per clear in a row before this one
+// not multiplied by the level: points per row a piece gets dropped by the player
+const SOFT_DROP_POINTS: u64 = 1;
+const HARD_DROP_POINTS: u64 = 2;
 
 const GHOST_PIECE_OPACITY: f32 = 0.35;
 
+const LOCK_DELAY: f64 = 0.5; // seconds a piece can sit on the stack before locking
+const MAX_MOVE_RESETS: u32 = 15; // moves/rotations that can restart the lock delay
+
+// FNV-1a, for Board::checksum
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+const LINES_PER_LEVEL: u32 = 10;
+pub const MAX_LEVEL: u32 = 20; // gravity stops speeding up here
+
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub enum GameState {
   Playing,
@@ -20,102 +38,509 @@ pub enum GameState {
   Over // donezo. player lost or quit
 }
 
+// what's in a cell of the locked stack. blocks remember which piece they came from
+// so they keep its color
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Cell {
+  Empty,
+  Block(Shape),
+  Garbage
+}
+
+// everything a player can do to their board. the frontend maps keys to these
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Action {
+  MoveLeft,
+  MoveRight,
+  SoftDrop,
+  HardDrop,
+  RotateCw,
+  RotateCcw,
+  Hold
+}
+
+// how a t-piece got into place, going by the 3-corner rule
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Spin {
+  None,
+  Mini, // only one of the corners it points at is filled
+  Full
+}
+
+// what the last piece to lock did
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Clear {
+  pub lines: usize,
+  pub spin: Spin,
+  pub all_clear: bool, // nothing left on the board afterwards
+  pub back_to_back: bool, // a difficult clear right after another one
+  pub combo: u32, // clears in a row, counting this one
+  pub points: u64
+}
+
+impl Clear {
+  // tetrises and t-spins that clear something. a run of these is back-to-back, and
+  // any other clear breaks it
+  pub fn difficult(&self) -> bool {
+    self.lines == 4 || (self.lines > 0 && self.spin != Spin::None)
+  }
+
+  // e.g. "T-SPIN MINI DOUBLE", for the frontend to show off
+  pub fn name(&self) -> String {
+    let mut words = Vec::new();
+    if self.back_to_back {
+      words.push("BACK-TO-BACK");
+    }
+    match self.spin {
+      Spin::Full => words.push("T-SPIN"),
+      Spin::Mini => words.push("T-SPIN MINI"),
+      Spin::None => ()
+    }
+    match self.lines {
+      0 => (),
+      1 => words.push("SINGLE"),
+      2 => words.push("DOUBLE"),
+      3 => words.push("TRIPLE"),
+      _ if self.spin == Spin::None => words.push("TETRIS"),
+      _ => words.push("QUAD")
+    }
+    if self.all_clear {
+      words.push("ALL CLEAR");
+    }
+    words.join(" ")
+  }
+}
+
+// how many garbage lines a clear sends to the opponent
+#[derive(Debug, Clone)]
+pub struct AttackTable {
+  pub lines: [u32; 4], // 1st entry is # of lines sent for a single, etc. minis use these too
+  pub tspin_lines: [u32; 4], // same but for t-spins. 1st entry is a t-spin that clears nothing
+  pub combo: Vec<u32>, // bonus for the nth clear in a row. last entry is used past the end
+  pub back_to_back: u32, // bonus for a back-to-back clear
+  pub all_clear: u32 // bonus for emptying the board
+}
+
+impl Default for AttackTable {
+  // guideline numbers
+  fn default() -> AttackTable {
+    AttackTable {
+      lines: [0, 1, 2, 4],
+      tspin_lines: [0, 2, 4, 6],
+      combo: vec![0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 4, 5],
+      back_to_back: 1,
+      all_clear: 10
+    }
+  }
+}
+
+impl AttackTable {
+  pub fn attack(&self, clear: &Clear) -> u32 {
+    let mut attack = if clear.spin == Spin::Full {
+      self.tspin_lines[clear.lines.min(3)]
+    } else if clear.lines > 0 {
+      self.lines[clear.lines.min(4) - 1]
+    } else {
+      0
+    };
+    if clear.combo > 0 && !self.combo.is_empty() {
+      let i = ((clear.combo - 1) as usize).min(self.combo.len() - 1);
+      attack += self.combo[i];
+    }
+    if clear.back_to_back {
+      attack += self.back_to_back;
+    }
+    if clear.all_clear {
+      attack += self.all_clear;
+    }
+    attack
+  }
+}
+
+// everything needed to rebuild a board exactly as it was, e.g. to send it over the
+// network. the ghost piece and row counts aren't in here since they can be worked out
+#[derive(PartialEq, Debug, Clone)]
+pub struct Snapshot {
+  pub cells: [[Cell; BOARD_WIDTH as usize]; BOARD_HEIGHT as usize],
+  pub current_piece: Tetromino,
+  pub next_piece: Shape,
+  pub hold_piece: Option<Shape>,
+  pub hold_used: bool,
+  pub state: GameState,
+  pub score: u64,
+  pub level: u32,
+  pub lines_cleared: u32,
+  pub combo: u32,
+  pub back_to_back: bool,
+  pub last_clear: Option<Clear>,
+  pub last_kick: Option<usize>,
+  pub gravity_timer: f64,
+  pub lock_timer: f64,
+  pub move_resets: u32,
+  pub lowest_y: f64,
+  pub pending_garbage: Vec<(u32, usize)>,
+  // the bag: the rng gets rebuilt from the seed and shuffled bags_drawn times
+  pub seed: u64,
+  pub bags_drawn: u32,
+  pub bag: Vec<Shape>
+}
+
+// index of the board that won, once at most one is still going. if the last ones
+// all died at once, the best score wins
+pub fn winner(boards: &[Board]) -> Option<usize> {
+  let alive: Vec<usize> = (0..boards.len())
+    .filter(|&i| boards[i].state != GameState::Over)
+    .collect();
+  match alive.len() {
+    0 => (0..boards.len()).max_by_key(|&i| boards[i].score),
+    1 => Some(alive[0]),
+    _ => None
+  }
+}
+
+// where an attack from board `from` goes: the next board after it that's still playing
+pub fn garbage_target(boards: &[Board], from: usize) -> Option<usize> {
+  (1..boards.len())
+    .map(|i| (from + i) % boards.len())
+    .find(|&i| boards[i].state == GameState::Playing)
+}
+
 pub struct Board {
-  pub cells: [[u8; BOARD_WIDTH as usize]; BOARD_HEIGHT as usize],
+  pub cells: [[Cell; BOARD_WIDTH as usize]; BOARD_HEIGHT as usize],
   pub current_piece: Tetromino, // current active Tetromino
   pub ghost_piece: Tetromino, // ghost piece to display at bottom
   pub next_piece: Tetromino,
+  pub hold_piece: Option<Tetromino>, // piece put aside with Action::Hold
   pub state: GameState,
   pub score: u64,
+  pub level: u32,
+  pub lines_cleared: u32,
+
+  // index into the kick table of the last successful rotation. cleared when the piece
+  // moves any other way, so it only says something if rotating was the last thing done
+  pub last_kick: Option<usize>,
+
+  pub lock_delay: f64,
+  pub max_move_resets: u32,
+
+  pub attack_table: AttackTable,
+  pub combo: u32, // # of pieces in a row that cleared lines
+  pub back_to_back: bool, // the last clear was a difficult one, so the next could be back-to-back
+  pub last_clear: Option<Clear>, // what the last piece to lock did, if it cleared or spun
 
   pub token: i32, // token identifier for use by the game server
   pub new_block: bool, // true for 1 iteration if new block, false otherwise
 
   // line_counts[i] = # of filled blocks in row i
   line_counts: [i64; BOARD_HEIGHT as usize],
+  // seconds since the piece last fell a row
+  gravity_timer: f64,
+  // seconds the piece has been resting on the stack
+  lock_timer: f64,
+  // times the lock delay got restarted by moving this piece
+  move_resets: u32,
+  // lowest row the piece has been on. falling past it gives back the lock delay
+  lowest_y: f64,
+  // garbage sent our way as (lines, hole column), waiting for the next lock
+  pending_garbage: Vec<(u32, usize)>,
+  // garbage we've sent that the frontend hasn't picked up yet
+  outgoing_attack: u32,
+  // only one hold allowed per piece, reset when a piece locks
+  hold_used: bool,
   // for "random bag" generation of the next tetromino
   tetrominos_bag: Vec<Tetromino>,
+  // seeded so that every client shuffles the same bags for a given match
+  rng: Isaac64Rng,
+  seed: u64,
+  // how many bags the rng has shuffled, so a snapshot can catch a new rng up
+  bags_drawn: u32,
 }
 
 impl Board {
   pub fn init_board() -> Board {
+    Board::with_seed(::rand::random())
+  }
+
+  // boards made from the same seed will always get the same pieces in the same order
+  pub fn with_seed(seed: u64) -> Board {
+    let mut rng = Isaac64Rng::from_seed(&[seed]);
     let mut bag = TETROMINOS.to_vec();
-    ::rand::thread_rng().shuffle(&mut bag);
+    rng.shuffle(&mut bag);
 
     let first_piece: Tetromino = bag.remove(0);
     let next_piece: Tetromino = bag.remove(0);
-    let mut ghost_piece: Tetromino = first_piece.clone();
-    ghost_piece.y_offset = BOARD_HEIGHT as f64 - ghost_piece.block_height();
-    // some shapes are drawn starting from 2nd, not 1st row in block
-    if ghost_piece.name == Shape::T || ghost_piece.name == Shape::S || ghost_piece.name == Shape::Z {
-      ghost_piece.y_offset -= 1.0;
-    }
-    ghost_piece.color[3] = GHOST_PIECE_OPACITY; // transparent
 
-    Board {
-      cells: [[0; BOARD_WIDTH as usize]; BOARD_HEIGHT as usize],
+    let mut board = Board {
+      cells: [[Cell::Empty; BOARD_WIDTH as usize]; BOARD_HEIGHT as usize],
       current_piece: first_piece,
-      ghost_piece: ghost_piece,
+      ghost_piece: first_piece,
       next_piece: next_piece,
+      hold_piece: None,
       state: GameState::Playing,
       score: 0,
+      level: 1,
+      lines_cleared: 0,
+
+      last_kick: None,
+
+      lock_delay: LOCK_DELAY,
+      max_move_resets: MAX_MOVE_RESETS,
+
+      attack_table: AttackTable::default(),
+      combo: 0,
+      back_to_back: false,
+      last_clear: None,
 
       token: 0,
       new_block: true,
 
       line_counts: [0; BOARD_HEIGHT as usize],
-      tetrominos_bag: bag
-    }
-  }
-
-  pub fn handle_key_press(&mut self, inp: &Input) {
-    match *inp {
-      Input::Press(but) => {
-        match but {
-          Button::Keyboard(Key::Up) => {
-            self.current_piece.rotate_right();
-            self.ghost_piece.rotate_right();
-            // undo rotation if it was invalid...lol
-            if self.current_piece_out_of_bounds() {
-              self.current_piece.rotate_left();
-              self.ghost_piece.rotate_left();
-            }
-            self.update_ghost_piece();
-          }
-          Button::Keyboard(Key::Left) => {
-            if self.can_move_current_piece_left() {
-              self.current_piece.move_left();
-              self.update_ghost_piece();
-            }
-          }
-          Button::Keyboard(Key::Right) => {
-            if self.can_move_current_piece_right() {
-              self.current_piece.move_right();
-              self.update_ghost_piece();
-            }
-          }
-          Button::Keyboard(Key::Down) => {
-            if self.can_move_piece_down(&self.current_piece) {
-              self.current_piece.move_down();
-            }
-          }
-          Button::Keyboard(Key::Space) => {
-            // pressing spacebar drops piece to bottom
-            while self.can_move_piece_down(&self.current_piece) {
-              self.current_piece.move_down();
-            }
-          }
-          _ => {}
+      gravity_timer: 0.0,
+      lock_timer: 0.0,
+      move_resets: 0,
+      lowest_y: first_piece.y_offset,
+      pending_garbage: Vec::new(),
+      outgoing_attack: 0,
+      hold_used: false,
+      tetrominos_bag: bag,
+      rng: rng,
+      seed: seed,
+      bags_drawn: 1
+    };
+    board.update_ghost_piece();
+    board
+  }
+
+  // start over for a new round, with fresh pieces from `seed`. the rules the board
+  // was set up with (lock delay, attack table, etc.) stay as they were
+  pub fn reset(&mut self, seed: u64) {
+    let mut board = Board::with_seed(seed);
+    board.lock_delay = self.lock_delay;
+    board.max_move_resets = self.max_move_resets;
+    board.attack_table = self.attack_table.clone();
+    board.token = self.token;
+    *self = board;
+  }
+
+  pub fn snapshot(&self) -> Snapshot {
+    Snapshot {
+      cells: self.cells,
+      current_piece: self.current_piece,
+      next_piece: self.next_piece.name,
+      hold_piece: self.hold_piece.map(|p| p.name),
+      hold_used: self.hold_used,
+      state: self.state,
+      score: self.score,
+      level: self.level,
+      lines_cleared: self.lines_cleared,
+      combo: self.combo,
+      back_to_back: self.back_to_back,
+      last_clear: self.last_clear,
+      last_kick: self.last_kick,
+      gravity_timer: self.gravity_timer,
+      lock_timer: self.lock_timer,
+      move_resets: self.move_resets,
+      lowest_y: self.lowest_y,
+      pending_garbage: self.pending_garbage.clone(),
+      seed: self.seed,
+      bags_drawn: self.bags_drawn,
+      bag: self.tetrominos_bag.iter().map(|p| p.name).collect()
+    }
+  }
+
+  // puts the board back the way it was when the snapshot was taken. settings like
+  // the lock delay and attack table stay as they are
+  pub fn restore(&mut self, snapshot: &Snapshot) {
+    self.cells = snapshot.cells;
+    for row in 0..BOARD_HEIGHT as usize {
+      self.line_counts[row] = self.cells[row].iter().filter(|&&cell| cell != Cell::Empty).count() as i64;
+    }
+    self.current_piece = snapshot.current_piece;
+    self.next_piece = Tetromino::spawn(snapshot.next_piece);
+    self.hold_piece = snapshot.hold_piece.map(Tetromino::spawn);
+    self.hold_used = snapshot.hold_used;
+    self.state = snapshot.state;
+    self.score = snapshot.score;
+    self.level = snapshot.level;
+    self.lines_cleared = snapshot.lines_cleared;
+    self.combo = snapshot.combo;
+    self.back_to_back = snapshot.back_to_back;
+    self.last_clear = snapshot.last_clear;
+    self.last_kick = snapshot.last_kick;
+    self.gravity_timer = snapshot.gravity_timer;
+    self.lock_timer = snapshot.lock_timer;
+    self.move_resets = snapshot.move_resets;
+    self.lowest_y = snapshot.lowest_y;
+    self.pending_garbage = snapshot.pending_garbage.clone();
+
+    // replay the shuffles so the next bag comes out the same
+    self.rng = Isaac64Rng::from_seed(&[snapshot.seed]);
+    for _ in 0..snapshot.bags_drawn {
+      let mut bag = TETROMINOS.to_vec();
+      self.rng.shuffle(&mut bag);
+    }
+    self.seed = snapshot.seed;
+    self.bags_drawn = snapshot.bags_drawn;
+    self.tetrominos_bag = snapshot.bag.iter().map(|&shape| Tetromino::spawn(shape)).collect();
+    self.update_ghost_piece();
+  }
+
+  pub fn from_snapshot(snapshot: &Snapshot) -> Board {
+    let mut board = Board::with_seed(snapshot.seed);
+    board.restore(snapshot);
+    board
+  }
+
+  // cheap hash of the stack, current piece, score and bag. two copies of a board that
+  // hash differently have drifted apart
+  pub fn checksum(&self) -> u64 {
+    let mut values: Vec<u64> = Vec::new();
+    for row in self.cells.iter() {
+      for cell in row.iter() {
+        values.push(match *cell {
+          Cell::Empty => 0,
+          Cell::Garbage => 1,
+          Cell::Block(shape) => 2 + shape as u64
+        });
+      }
+    }
+    let piece = self.current_piece;
+    values.push(piece.name as u64);
+    values.push(piece.rotation as u64);
+    values.push(piece.x_offset as i64 as u64);
+    values.push(piece.y_offset as i64 as u64);
+    values.push(self.score);
+    values.push(self.seed);
+    values.push(self.bags_drawn as u64);
+    for piece in self.tetrominos_bag.iter() {
+      values.push(piece.name as u64);
+    }
+
+    let mut hash = FNV_OFFSET_BASIS;
+    for value in values {
+      for i in 0..8 {
+        hash ^= (value >> (8 * i)) & 0xff;
+        hash = hash.wrapping_mul(FNV_PRIME);
+      }
+    }
+    hash
+  }
+
+  // stops (or restarts) gravity and the lock delay. boards that are over stay over
+  pub fn pause(&mut self, paused: bool) {
+    match (self.state, paused) {
+      (GameState::Playing, true) => self.state = GameState::Paused,
+      (GameState::Paused, false) => self.state = GameState::Playing,
+      _ => ()
+    }
+  }
+
+  // paused boards and boards that are over don't take any input
+  pub fn apply(&mut self, action: Action) {
+    if self.state != GameState::Playing {
+      return
+    }
+    match action {
+      Action::RotateCw => {
+        self.rotate_current_piece(true);
+      }
+      Action::RotateCcw => {
+        self.rotate_current_piece(false);
+      }
+      Action::MoveLeft => {
+        if self.can_move_current_piece_left() {
+          self.current_piece.move_left();
+          self.last_kick = None;
+          self.reset_lock_timer();
+          self.update_ghost_piece();
+        }
+      }
+      Action::MoveRight => {
+        if self.can_move_current_piece_right() {
+          self.current_piece.move_right();
+          self.last_kick = None;
+          self.reset_lock_timer();
+          self.update_ghost_piece();
+        }
+      }
+      Action::SoftDrop => {
+        if self.can_move_piece_down(&self.current_piece) {
+          self.current_piece.move_down();
+          self.last_kick = None;
+          self.score += SOFT_DROP_POINTS;
+          self.check_lowest_row();
+        }
+      }
+      Action::HardDrop => {
+        // drops piece straight to the bottom and locks it right away
+        while self.can_move_piece_down(&self.current_piece) {
+          self.current_piece.move_down();
+          self.last_kick = None;
+          self.score += HARD_DROP_POINTS;
+        }
+        if self.state == GameState::Playing {
+          self.lock_piece();
         }
       }
-      _ => {}
+      Action::Hold => {
+        self.hold_current_piece();
+      }
+    }
+  }
+
+  // swaps the current piece with the held one (or the next piece if nothing is held yet).
+  // whatever goes into the hold slot comes back out at its spawn position
+  fn hold_current_piece(&mut self) {
+    if self.hold_used {
+      return
+    }
+    let held = Tetromino::spawn(self.current_piece.name);
+    match self.hold_piece {
+      Some(piece) => {
+        self.current_piece = Tetromino::spawn(piece.name);
+      }
+      None => {
+        self.current_piece = self.next_piece;
+        self.next_piece = self.get_next_piece();
+        self.new_block = true;
+      }
+    }
+    self.hold_piece = Some(held);
+    self.hold_used = true;
+    self.last_kick = None;
+    self.reset_piece_timers();
+    self.update_ghost_piece();
+  }
+
+  // rotates the current piece using the SRS kick tables. returns the index of the
+  // kick that worked (0 means it fit without kicking), or None if nothing fit
+  pub fn rotate_current_piece(&mut self, clockwise: bool) -> Option<usize> {
+    let mut rotated = self.current_piece;
+    if clockwise {
+      rotated.rotate_right();
+    } else {
+      rotated.rotate_left();
+    }
+    for (i, &(dx, dy)) in self.current_piece.kicks(clockwise).iter().enumerate() {
+      let mut kicked = rotated;
+      kicked.x_offset += dx as f64;
+      kicked.y_offset -= dy as f64; // kick tables have +y going up
+      if self.piece_fits(&kicked) {
+        self.current_piece = kicked;
+        self.last_kick = Some(i);
+        self.reset_lock_timer();
+        self.check_lowest_row();
+        self.update_ghost_piece();
+        return Some(i)
+      }
     }
+    None
   }
 
   // iterate thru board and clear the specified row
   fn clear_row(&mut self, row: usize) {
     for col in 0..BOARD_WIDTH {
-      self.cells[row][col as usize] = 0;
+      self.cells[row][col as usize] = Cell::Empty;
     }
     self.line_counts[row] = 0;
 
@@ -128,7 +553,8 @@ impl Board {
     }
   }
 
-  pub fn clear_line_if_needed(&mut self) {
+  // returns how many rows got cleared
+  pub fn clear_line_if_needed(&mut self) -> usize {
     // iterate through rows affected by current piece
     let mut rows_affected = Vec::new();
     let current_y = self.current_piece.y_offset;
@@ -150,10 +576,175 @@ impl Board {
       }
     }
     if rows_cleared > 0 {
-      self.score += SCORES_PER_LINE[rows_cleared - 1];
+      self.lines_cleared += rows_cleared as u32;
+      self.level = (self.lines_cleared / LINES_PER_LEVEL + 1).min(MAX_LEVEL);
+    }
+    rows_cleared
+  }
+
+  // 3-corner rule: a T that got into place by rotating, with at least 3 of the 4
+  // cells diagonal from its center filled in. it's only a mini if one of the two
+  // corners it points at is open, unless it got there with the last (biggest) kick
+  fn spin(&self) -> Spin {
+    let piece = self.current_piece;
+    let kick = match self.last_kick {
+      Some(kick) if piece.name == Shape::T => kick,
+      _ => return Spin::None
+    };
+    let center_x = piece.x_offset as i64 + 1;
+    let center_y = piece.y_offset as i64 + 1;
+    // corners clockwise from the top left, so the pair a T points at in rotation
+    // state r is r and r + 1
+    let corners = [(-1, -1), (1, -1), (1, 1), (-1, 1)];
+    let filled: Vec<bool> = corners.iter()
+      .map(|&(dx, dy)| self.is_filled(center_x + dx, center_y + dy))
+      .collect();
+    if filled.iter().filter(|&&f| f).count() < 3 {
+      Spin::None
+    } else if (filled[piece.rotation] && filled[(piece.rotation + 1) % 4]) || kick == 4 {
+      Spin::Full
+    } else {
+      Spin::Mini
+    }
+  }
+
+  // sorts out what a lock just did and what it's worth. `level` is the level the
+  // piece locked on, before any lines it cleared count towards the next one
+  fn classify(&self, lines: usize, spin: Spin, level: u32) -> Clear {
+    let all_clear = lines > 0 && self.line_counts.iter().all(|&count| count == 0);
+    let mut clear = Clear {
+      lines: lines,
+      spin: spin,
+      all_clear: all_clear,
+      back_to_back: false,
+      combo: self.combo,
+      points: 0
+    };
+    clear.back_to_back = self.back_to_back && clear.difficult();
+
+    let mut points = match spin {
+      Spin::None => LINE_POINTS[lines.min(4)],
+      Spin::Mini => MINI_TSPIN_POINTS[lines.min(2)],
+      Spin::Full => TSPIN_POINTS[lines.min(3)]
+    };
+    if clear.back_to_back {
+      points += points / 2;
+    }
+    if self.combo > 1 {
+      points += COMBO_POINTS * (self.combo - 1) as u64;
+    }
+    if all_clear {
+      points += ALL_CLEAR_POINTS[lines.min(4) - 1];
+    }
+    clear.points = points * level as u64;
+    clear
+  }
+
+  // walls and floor count as filled
+  fn is_filled(&self, x: i64, y: i64) -> bool {
+    if !(0..BOARD_WIDTH).contains(&x) || !(0..BOARD_HEIGHT).contains(&y) {
+      return true
+    }
+    self.cells[y as usize][x as usize] != Cell::Empty
+  }
+
+  // queue up garbage from an opponent. it rises the next time we lock a piece
+  // without clearing anything, unless we cancel it out by clearing lines first
+  pub fn queue_garbage(&mut self, lines: u32, hole_col: usize) {
+    if lines > 0 {
+      self.pending_garbage.push((lines, hole_col));
+    }
+  }
+
+  pub fn pending_garbage_lines(&self) -> u32 {
+    self.pending_garbage.iter().map(|&(lines, _)| lines).sum()
+  }
+
+  // garbage lines this board has sent since the last time this was called
+  pub fn take_attack(&mut self) -> u32 {
+    let attack = self.outgoing_attack;
+    self.outgoing_attack = 0;
+    attack
+  }
+
+  // uses up an attack on garbage that's waiting to come in. returns what's left over
+  fn cancel_pending_garbage(&mut self, mut attack: u32) -> u32 {
+    while attack > 0 && !self.pending_garbage.is_empty() {
+      if self.pending_garbage[0].0 <= attack {
+        attack -= self.pending_garbage[0].0;
+        self.pending_garbage.remove(0);
+      } else {
+        self.pending_garbage[0].0 -= attack;
+        attack = 0;
+      }
+    }
+    attack
+  }
+
+  // push `lines` rows of garbage up from the bottom, with a gap at hole_col
+  pub fn receive_garbage(&mut self, lines: u32, hole_col: usize) {
+    let lines = (lines as usize).min(BOARD_HEIGHT as usize);
+    if lines == 0 || hole_col >= BOARD_WIDTH as usize {
+      return
+    }
+    // anything pushed off the top means we topped out
+    for row in 0..lines {
+      if self.line_counts[row] > 0 {
+        self.state = GameState::Over;
+      }
+    }
+    for row in 0..(BOARD_HEIGHT as usize - lines) {
+      self.cells[row] = self.cells[row + lines];
+      self.line_counts[row] = self.line_counts[row + lines];
+    }
+    for row in (BOARD_HEIGHT as usize - lines)..(BOARD_HEIGHT as usize) {
+      for col in 0..BOARD_WIDTH as usize {
+        self.cells[row][col] = if col == hole_col { Cell::Empty } else { Cell::Garbage };
+      }
+      self.line_counts[row] = BOARD_WIDTH - 1;
+    }
+    // shove the current piece up out of the way if the garbage ran into it
+    while !self.piece_fits(&self.current_piece) && self.current_piece.y_offset > 0.0 {
+      self.current_piece.y_offset -= 1.0;
+    }
+    self.update_ghost_piece();
+  }
+
+  // seconds it takes the piece to fall one row at the current level (guideline curve)
+  pub fn gravity_interval(&self) -> f64 {
+    let level = (self.level.min(MAX_LEVEL) - 1) as f64;
+    (0.8 - level * 0.007).powf(level)
+  }
+
+  // runs gravity and the lock delay for `dt` seconds of game time
+  pub fn update(&mut self, dt: f64) {
+    if self.state != GameState::Playing {
+      return
+    }
+    if self.can_move_piece_down(&self.current_piece) {
+      self.gravity_timer += dt;
+      let interval = self.gravity_interval();
+      while self.gravity_timer >= interval && self.can_move_piece_down(&self.current_piece) {
+        self.gravity_timer -= interval;
+        self.advance_board();
+      }
+    } else {
+      // resting on the stack. lock once the delay runs out, or right away if the
+      // piece has used up all its move resets
+      self.gravity_timer = 0.0;
+      self.lock_timer += dt;
+      if self.lock_timer >= self.lock_delay || self.move_resets >= self.max_move_resets {
+        self.lock_piece();
+      }
     }
   }
 
+  // one fixed step of the simulation. boards that get the same actions on the same
+  // ticks always end up the same, which is what lockstep needs
+  pub fn tick(&mut self) {
+    self.update(1.0 / TICKS_PER_SECOND as f64);
+  }
+
   pub fn advance_board(&mut self) {
     if self.state != GameState::Playing {
       return
@@ -161,7 +752,7 @@ impl Board {
     // check if game is over or not
     // check top row of board and see if any of them are filled?
     for col in 0..self.cells[0].len() {
-      if self.cells[0][col] == 1{
+      if self.cells[0][col] != Cell::Empty {
         self.state = GameState::Over;
         return
       }
@@ -171,35 +762,105 @@ impl Board {
     if self.can_move_piece_down(&self.current_piece) {
       self.new_block = false;
       self.current_piece.move_down();
+      // falling counts as the last move, same as dropping it yourself
+      self.last_kick = None;
+      self.check_lowest_row();
     } else {
-      // add piece to board cells
-      self.set_piece_on_board();
-      self.score += 10;
-      
-      // clear line if necessary
-      self.clear_line_if_needed();
+      self.lock_piece();
+    }
+  }
 
-      // get new piece
-      self.current_piece = self.next_piece;
-      self.next_piece = self.get_next_piece();
-      self.new_block = true;
-      self.update_ghost_piece();
+  // successful moves and rotations on the stack buy the piece more time, up to a limit
+  fn reset_lock_timer(&mut self) {
+    if self.lock_timer > 0.0 && self.move_resets < self.max_move_resets {
+      self.lock_timer = 0.0;
+      self.move_resets += 1;
     }
   }
 
-  fn can_move_piece_down(&self, piece: &Tetromino) -> bool {
-    if piece.bottommost() + 1.0 >= BOARD_HEIGHT as f64 {
-      return false
+  // falling lower than it's been before gives the piece a fresh lock delay
+  fn check_lowest_row(&mut self) {
+    if self.current_piece.y_offset > self.lowest_y {
+      self.lowest_y = self.current_piece.y_offset;
+      self.lock_timer = 0.0;
+      self.move_resets = 0;
     }
-    // check if piece will intersect with a piece already on the board
+  }
+
+  // for a piece that just spawned
+  fn reset_piece_timers(&mut self) {
+    self.gravity_timer = 0.0;
+    self.lock_timer = 0.0;
+    self.move_resets = 0;
+    self.lowest_y = self.current_piece.y_offset;
+  }
+
+  fn lock_piece(&mut self) {
+    // has to be checked before the piece is locked and lines are gone
+    let spin = self.spin();
+    let level = self.level;
+
+    // add piece to board cells
+    self.set_piece_on_board();
+
+    // clear line if necessary
+    let rows_cleared = self.clear_line_if_needed();
+    if rows_cleared > 0 {
+      self.combo += 1;
+    } else {
+      self.combo = 0;
+    }
+
+    let clear = self.classify(rows_cleared, spin, level);
+    self.score += clear.points;
+    if rows_cleared > 0 {
+      self.back_to_back = clear.difficult();
+    }
+    self.last_clear = if rows_cleared > 0 || spin != Spin::None {
+      Some(clear)
+    } else {
+      None
+    };
+
+    // attack the opponent, after cancelling out whatever garbage is coming at us
+    let attack = self.attack_table.attack(&clear);
+    let attack = self.cancel_pending_garbage(attack);
+    self.outgoing_attack += attack;
+    if rows_cleared == 0 {
+      let garbage: Vec<(u32, usize)> = self.pending_garbage.drain(..).collect();
+      for (lines, hole_col) in garbage {
+        self.receive_garbage(lines, hole_col);
+      }
+    }
+
+    // get new piece
+    self.current_piece = self.next_piece;
+    self.next_piece = self.get_next_piece();
+    self.new_block = true;
+    self.hold_used = false;
+    self.last_kick = None;
+    self.reset_piece_timers();
+    self.update_ghost_piece();
+
+    // nowhere to put the new piece
+    if !self.piece_fits(&self.current_piece) {
+      self.state = GameState::Over;
+    }
+  }
+
+  // true if every block of the piece is on the board and not on top of a filled cell
+  fn piece_fits(&self, piece: &Tetromino) -> bool {
     let current_x = piece.x_offset;
     let current_y = piece.y_offset;
     for row in 0..piece.blocks.len() {
       for col in 0..piece.blocks[0].len() {
         if piece.blocks[row][col] == 1 {
           let board_x: i64 = (col as i64) + (current_x as i64);
-          let board_y: i64 = (row as i64) + (current_y as i64) + 1;
-          if self.cells[board_y as usize][board_x as usize] == 1 {
+          let board_y: i64 = (row as i64) + (current_y as i64);
+          if !(0..BOARD_WIDTH).contains(&board_x) || !(0..BOARD_HEIGHT).contains(&board_y) {
+            return false;
+          }
+          if self.cells[board_y as usize][board_x as usize] != Cell::Empty {
             return false;
           }
         }
@@ -208,53 +869,34 @@ impl Board {
     true
   }
 
+  fn can_move_piece_down(&self, piece: &Tetromino) -> bool {
+    let mut moved = *piece;
+    moved.move_down();
+    self.piece_fits(&moved)
+  }
+
   fn can_move_current_piece_left(&self) -> bool {
-    if self.current_piece.leftmost() - 1.0 < 0.0 {
-      return false;
-    }
-    // check if will intersect with a piece already on the board
-    let current_x = self.current_piece.x_offset;
-    let current_y = self.current_piece.y_offset;
-    for row in 0..self.current_piece.blocks.len() {
-      for col in 0..self.current_piece.blocks[0].len() {
-        if self.current_piece.blocks[row][col] == 1 {
-          let board_x: i64 = (col as i64) + (current_x as i64) - 1;
-          let board_y: i64 = (row as i64) + (current_y as i64);
-          if self.cells[board_y as usize][board_x as usize] == 1 {
-            return false;
-          }
-        }
-      }
-    }
-    true
+    let mut moved = self.current_piece;
+    moved.move_left();
+    self.piece_fits(&moved)
   }
 
   fn can_move_current_piece_right(&self) -> bool {
-    if self.current_piece.rightmost() + 1.0 >= BOARD_WIDTH as f64  {
-      return false;
-    }
-    // check if will intersect with a piece already on the board
-    let current_x = self.current_piece.x_offset;
-    let current_y = self.current_piece.y_offset;
-    for row in 0..self.current_piece.blocks.len() {
-      for col in 0..self.current_piece.blocks[0].len() {
-        if self.current_piece.blocks[row][col] == 1 {
-          let board_x: i64 = (col as i64) + (current_x as i64) + 1;
-          let board_y: i64 = (row as i64) + (current_y as i64);
-          if self.cells[board_y as usize][board_x as usize] == 1 {
-            return false;
-          }
-        }
-      }
-    }
-    true
+    let mut moved = self.current_piece;
+    moved.move_right();
+    self.piece_fits(&moved)
   }
 
-  fn current_piece_out_of_bounds(&self) -> bool {
-    if self.current_piece.leftmost() < 0.0 || self.current_piece.rightmost() >= BOARD_WIDTH as f64 || self.current_piece.bottommost() >= BOARD_HEIGHT as f64 {
-      return true
+  // for setting up a board by hand. keeps the row counts and ghost piece in sync
+  pub fn set_cell(&mut self, row: usize, col: usize, cell: Cell) {
+    if self.cells[row][col] != Cell::Empty {
+      self.line_counts[row] -= 1;
     }
-    false
+    if cell != Cell::Empty {
+      self.line_counts[row] += 1;
+    }
+    self.cells[row][col] = cell;
+    self.update_ghost_piece();
   }
 
   pub fn set_piece_on_board(&mut self) {
@@ -265,7 +907,7 @@ impl Board {
         if self.current_piece.blocks[row][col] == 1 {
           let x: i64 = (col as i64) + (current_x as i64);
           let y: i64 = (row as i64) + (current_y as i64);
-          self.cells[y as usize][x as usize] = 1;
+          self.cells[y as usize][x as usize] = Cell::Block(self.current_piece.name);
           self.line_counts[y as usize] += 1;
         }
       }
@@ -275,21 +917,18 @@ impl Board {
   pub fn get_next_piece(&mut self) -> Tetromino {
     if self.tetrominos_bag.len() == 0 {
       self.tetrominos_bag = TETROMINOS.to_vec();
-      ::rand::thread_rng().shuffle(&mut self.tetrominos_bag);
+      self.rng.shuffle(&mut self.tetrominos_bag);
+      self.bags_drawn += 1;
     }
     self.tetrominos_bag.remove(0)
   }
 
   // helper function to update position of ghost piece w/ current piece
   pub fn update_ghost_piece(&mut self) {
-    if self.current_piece.name != self.ghost_piece.name {
-      self.ghost_piece = self.current_piece.clone();
-      self.ghost_piece.color[3] = GHOST_PIECE_OPACITY;
-    }
-    self.ghost_piece.y_offset = 0.0;
-    self.ghost_piece.x_offset = self.current_piece.x_offset;
+    self.ghost_piece = self.current_piece;
+    self.ghost_piece.color[3] = GHOST_PIECE_OPACITY; // transparent
     while self.can_move_piece_down(&self.ghost_piece) {
       self.ghost_piece.move_down();
     }
   }
-}
+}
\ No newline at end of file
@@ -0,0 +1,141 @@
+// client settings, from the command line or the environment. the command line wins
+// when both say something
+use std::env;
+
+use super::block::Result;
+use super::protocol;
+
+pub const DEFAULT_SERVER: &str = "ws://127.0.0.1:3012";
+pub const DEFAULT_BLOCK_SIZE: i64 = 30; // pixels per cell
+
+// anything outside this and the window is unplayable
+pub const MIN_BLOCK_SIZE: i64 = 10;
+pub const MAX_BLOCK_SIZE: i64 = 60;
+
+pub const USAGE: &str = "\
+usage: tetris [options]
+
+  --server URL    server to play on (TETRIS_SERVER, default ws://127.0.0.1:3012)
+  --name NAME     what to call you (TETRIS_NAME)
+  --scale PIXELS  size of a block, 10 to 60 (TETRIS_SCALE, default 30)
+  --font PATH     .ttf file to draw text with (TETRIS_FONT)
+  --offline       play by yourself without a server (TETRIS_OFFLINE=1)
+  --spectate      watch the game on the server instead of playing in it
+  --record FILE   save each round to FILE.1, FILE.2, ... (TETRIS_RECORD)
+  --replay FILE   watch a saved game instead of playing
+  --headless      with --replay, just play it through and check the scores
+  --help          show this";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+  pub server: String, // websocket url
+  pub name: Option<String>,
+  pub block_size: i64,
+  pub font: Option<String>, // None means look for Lato-Light.ttf
+  pub offline: bool,
+  pub spectate: bool,
+  pub record: Option<String>, // where to save replays
+  pub replay: Option<String>, // replay to watch
+  pub headless: bool,
+  pub help: bool
+}
+
+impl Default for Config {
+  fn default() -> Config {
+    Config {
+      server: DEFAULT_SERVER.to_string(),
+      name: None,
+      block_size: DEFAULT_BLOCK_SIZE,
+      font: None,
+      offline: false,
+      spectate: false,
+      record: None,
+      replay: None,
+      headless: false,
+      help: false
+    }
+  }
+}
+
+impl Config {
+  // settings for this run of the program
+  pub fn from_env() -> Result<Config> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    Config::parse(&args, |name| env::var(name).ok())
+  }
+
+  // `args` without the program name. `var` looks up an environment variable
+  pub fn parse<F>(args: &[String], var: F) -> Result<Config>
+    where F: Fn(&str) -> Option<String> {
+    let mut config = Config::default();
+
+    if let Some(server) = var("TETRIS_SERVER") {
+      config.server = server_url(&server)?;
+    }
+    config.name = var("TETRIS_NAME");
+    if let Some(scale) = var("TETRIS_SCALE") {
+      config.block_size = block_size(&scale)?;
+    }
+    config.font = var("TETRIS_FONT");
+    if let Some(offline) = var("TETRIS_OFFLINE") {
+      config.offline = !(offline.is_empty() || offline == "0" || offline == "false");
+    }
+    config.record = var("TETRIS_RECORD");
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+      match arg.as_str() {
+        "--server" => config.server = server_url(value(&mut args, arg)?)?,
+        "--name" => config.name = Some(value(&mut args, arg)?.to_string()),
+        "--scale" => config.block_size = block_size(value(&mut args, arg)?)?,
+        "--font" => config.font = Some(value(&mut args, arg)?.to_string()),
+        "--offline" => config.offline = true,
+        "--spectate" => config.spectate = true,
+        "--record" => config.record = Some(value(&mut args, arg)?.to_string()),
+        "--replay" => config.replay = Some(value(&mut args, arg)?.to_string()),
+        "--headless" => config.headless = true,
+        "-h" | "--help" => config.help = true,
+        _ => return Err(format!("unknown option: {}", arg))
+      }
+    }
+    if let Some(ref name) = config.name {
+      protocol::check_name(name)?;
+    }
+    if config.spectate && config.offline {
+      return Err("there's nothing to spectate offline".to_string());
+    }
+    if config.headless && config.replay.is_none() {
+      return Err("--headless needs a --replay to play".to_string());
+    }
+    Ok(config)
+  }
+}
+
+fn value<'a, I>(args: &mut I, option: &str) -> Result<&'a str>
+  where I: Iterator<Item = &'a String> {
+  match args.next() {
+    Some(v) => Ok(v),
+    None => Err(format!("{} needs a value", option))
+  }
+}
+
+// "host:port" is fine too, it's a websocket either way
+fn server_url(server: &str) -> Result<String> {
+  if server.is_empty() {
+    return Err("server address is empty".to_string());
+  }
+  if server.starts_with("ws://") || server.starts_with("wss://") {
+    Ok(server.to_string())
+  } else if server.contains("://") {
+    Err(format!("server address has to be a ws:// or wss:// url: {}", server))
+  } else {
+    Ok(format!("ws://{}", server))
+  }
+}
+
+fn block_size(scale: &str) -> Result<i64> {
+  match scale.parse::<i64>() {
+    Ok(n) if (MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&n) => Ok(n),
+    _ => Err(format!("scale must be a number from {} to {}, got {}", MIN_BLOCK_SIZE, MAX_BLOCK_SIZE, scale))
+  }
+}
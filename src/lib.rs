@@ -0,0 +1,19 @@
+// the game itself: boards, pieces and all the rules. nothing in here needs a window
+// or a network connection, so it can be run headless (tests, the server, etc.)
+extern crate rand;
+
+pub mod block;
+pub mod board;
+pub mod config;
+pub mod lockstep;
+pub mod protocol;
+pub mod replay;
+
+pub const BOARD_WIDTH: i64 = 10; // 10 cells across
+pub const BOARD_HEIGHT: i64 = 22; // 22 cells up n down
+
+// the simulation runs in fixed steps of 1 / TICKS_PER_SECOND seconds
+pub const TICKS_PER_SECOND: u64 = 60;
+
+// most boards a game (and so a replay) can have
+pub const MAX_PLAYERS: usize = 8;
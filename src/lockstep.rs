@@ -0,0 +1,311 @@
+use std::collections::BTreeMap;
+use std::mem;
+
+use rand::{Rng, SeedableRng, Isaac64Rng};
+
+use super::BOARD_WIDTH;
+use super::TICKS_PER_SECOND;
+use super::block::Result;
+use super::board;
+use super::board::{Action, Board, Snapshot};
+use super::replay::{Event, Replay};
+
+// ticks between pressing a key and it happening. gives the input time to get to the
+// other players before they need it
+pub const INPUT_DELAY: u64 = 3;
+
+// ticks between asking to resume and the boards starting again, so everyone gets a
+// countdown
+pub const RESUME_COUNTDOWN: u64 = 3 * TICKS_PER_SECOND;
+
+// every board gets checksummed on ticks that are a multiple of this
+pub const CHECKSUM_INTERVAL: u64 = TICKS_PER_SECOND;
+
+// how far back a resync can go. older snapshots are no use
+const HISTORY_TICKS: u64 = 10 * TICKS_PER_SECOND;
+
+// what happened to a board on one tick, so it can be played again after a resync
+#[derive(Debug, Clone, Default)]
+struct Record {
+  actions: Vec<Action>, // applied before the board ticked
+  garbage: Vec<(u32, usize)>, // queued after
+  pause: Option<bool> // paused or resumed before anything else
+}
+
+// every client runs every board one tick at a time, and only moves on to a tick once
+// it has every player's inputs for it. since the boards are deterministic, they all
+// come out exactly the same everywhere
+pub struct Lockstep {
+  pub boards: Vec<Board>,
+  pub tick: u64, // # of ticks simulated so far
+
+  // inputs waiting to be applied, by tick, for each player
+  inputs: Vec<BTreeMap<u64, Vec<Action>>>,
+  // confirmed[i] = player i has sent all their inputs for every tick before this
+  confirmed: Vec<u64>,
+  // garbage holes. seeded like the boards so every client digs the same ones
+  rng: Isaac64Rng,
+
+  // the last HISTORY_TICKS ticks of each board
+  history: Vec<BTreeMap<u64, Record>>,
+  // our checksums of each board, by tick
+  checksums: Vec<BTreeMap<u64, u64>>,
+  // checksums the boards' owners sent for ticks we haven't got to yet
+  reported: Vec<BTreeMap<u64, u64>>,
+  // boards whose checksum didn't match their owner's
+  desynced: Vec<usize>,
+  // snapshots from the future, by the tick they're waiting for us to get to
+  resyncs: Vec<BTreeMap<u64, Snapshot>>,
+  // tick each player left the game on, if they have
+  left: Vec<Option<u64>>,
+  // pausing or resuming every board, by tick, and who asked
+  pauses: BTreeMap<u64, (usize, bool)>,
+  paused: bool,
+  // everything that's happened so far, to save for later
+  record: Replay
+}
+
+impl Lockstep {
+  pub fn new(num_players: usize, seed: u64) -> Lockstep {
+    Lockstep {
+      boards: (0..num_players).map(|_| Board::with_seed(seed)).collect(),
+      tick: 0,
+      inputs: vec![BTreeMap::new(); num_players],
+      // nobody can have pressed anything for the first few ticks
+      confirmed: vec![INPUT_DELAY; num_players],
+      rng: Isaac64Rng::from_seed(&[seed]),
+      history: vec![BTreeMap::new(); num_players],
+      checksums: vec![BTreeMap::new(); num_players],
+      reported: vec![BTreeMap::new(); num_players],
+      desynced: Vec::new(),
+      resyncs: vec![BTreeMap::new(); num_players],
+      left: vec![None; num_players],
+      pauses: BTreeMap::new(),
+      paused: false,
+      record: Replay::new(num_players, seed)
+    }
+  }
+
+  // start a new round on the same boards. players who've left stay out of it
+  pub fn reset(&mut self, seed: u64) {
+    let mut boards = mem::take(&mut self.boards);
+    for board in boards.iter_mut() {
+      board.reset(seed);
+    }
+    let left = self.left.clone();
+    *self = Lockstep::new(boards.len(), seed);
+    self.boards = boards;
+    for (i, left) in left.iter().enumerate() {
+      if left.is_some() {
+        self.remove_player(i, 0);
+      }
+    }
+  }
+
+  // queue up an input for `player` to happen on `tick`
+  pub fn add_input(&mut self, player: usize, tick: u64, action: Action) -> Result<()> {
+    if tick < self.confirmed[player] {
+      return Err(format!("input for tick {} came after player {} confirmed tick {}",
+                         tick, player + 1, self.confirmed[player]));
+    }
+    self.inputs[player].entry(tick).or_default().push(action);
+    Ok(())
+  }
+
+  // `player` wants every board paused (or going again) from `tick` on. like an input,
+  // it has to come before they confirm the tick
+  pub fn pause(&mut self, player: usize, tick: u64, paused: bool) -> Result<()> {
+    if tick < self.confirmed[player] {
+      return Err(format!("pause for tick {} came after player {} confirmed tick {}",
+                         tick, player + 1, self.confirmed[player]));
+    }
+    self.pauses.insert(tick, (player, paused));
+    Ok(())
+  }
+
+  // whether the boards are paused, or are about to be
+  pub fn paused(&self) -> bool {
+    match self.pauses.values().last() {
+      Some(&(_, paused)) => paused,
+      None => self.paused
+    }
+  }
+
+  // ticks until the boards go again, if they're paused and somebody's asked to resume
+  pub fn resuming_in(&self) -> Option<u64> {
+    if !self.paused {
+      return None
+    }
+    self.pauses.iter()
+      .find(|&(_, &(_, paused))| !paused)
+      .map(|(&tick, _)| tick.saturating_sub(self.tick))
+  }
+
+  // `player` has sent everything they're going to for ticks before `tick`
+  pub fn confirm(&mut self, player: usize, tick: u64) {
+    if tick > self.confirmed[player] {
+      self.confirmed[player] = tick;
+    }
+  }
+
+  // `player` is gone. their board stops on `tick`, and nobody waits on them after that
+  pub fn remove_player(&mut self, player: usize, tick: u64) {
+    self.left[player] = Some(tick);
+    self.record.events.push((tick, Event::Left(player)));
+  }
+
+  // the game so far, for playing back later
+  pub fn replay(&self) -> Replay {
+    let mut replay = self.record.clone();
+    replay.events.sort_by_key(|&(tick, _)| tick);
+    replay.length = self.tick;
+    replay.results = self.boards.iter().map(|b| (b.score, b.checksum())).collect();
+    replay
+  }
+
+  // first tick we're still waiting on somebody for
+  pub fn confirmed_tick(&self) -> u64 {
+    // players who've left don't hold anybody up. the server only says they've gone
+    // once it's passed on everything they sent
+    self.confirmed.iter().zip(self.left.iter())
+      .filter(|&(_, left)| left.is_none())
+      .map(|(&confirmed, _)| confirmed)
+      .min().unwrap_or(self.tick)
+  }
+
+  // simulate every tick that everyone has confirmed. returns how many that was
+  pub fn advance(&mut self) -> u64 {
+    let start = self.tick;
+    while self.tick < self.confirmed_tick() {
+      self.step();
+    }
+    self.tick - start
+  }
+
+  // our checksum of `player`'s board from after `tick` ticks, if we still have it
+  pub fn checksum(&self, player: usize, tick: u64) -> Option<u64> {
+    self.checksums[player].get(&tick).cloned()
+  }
+
+  // the owner of a board says this is what it should hash to on `tick`
+  pub fn report_checksum(&mut self, player: usize, tick: u64, checksum: u64) {
+    if tick > self.tick {
+      self.reported[player].insert(tick, checksum);
+    } else if let Some(ours) = self.checksum(player, tick) {
+      self.check(player, ours, checksum);
+    }
+  }
+
+  // boards that have come out different from their owner's since the last call
+  pub fn take_desyncs(&mut self) -> Vec<usize> {
+    self.desynced.drain(..).collect()
+  }
+
+  // put `player`'s board back the way its owner had it after `tick` ticks
+  pub fn resync(&mut self, player: usize, tick: u64, snapshot: &Snapshot) -> Result<()> {
+    if tick > self.tick {
+      self.resyncs[player].insert(tick, snapshot.clone());
+      return Ok(())
+    }
+    if tick + HISTORY_TICKS < self.tick {
+      return Err(format!("resync for tick {} is too old, we're on tick {}", tick, self.tick));
+    }
+    // catch the board back up to everybody else with what happened to it since
+    let board = &mut self.boards[player];
+    board.restore(snapshot);
+    for t in tick..self.tick {
+      let record = self.history[player].get(&t).cloned().unwrap_or_default();
+      if let Some(paused) = record.pause {
+        board.pause(paused);
+      }
+      for action in record.actions {
+        if board.state == board::GameState::Playing {
+          board.apply(action);
+        }
+      }
+      board.tick();
+      // this already went wherever it was going the first time round
+      board.take_attack();
+      for (lines, hole_col) in record.garbage {
+        board.queue_garbage(lines, hole_col);
+      }
+    }
+    Ok(())
+  }
+
+  fn check(&mut self, player: usize, ours: u64, theirs: u64) {
+    if ours != theirs && !self.desynced.contains(&player) {
+      self.desynced.push(player);
+    }
+  }
+
+  fn step(&mut self) {
+    let tick = self.tick;
+    let pause = self.pauses.remove(&tick).map(|(player, paused)| {
+      self.record.events.push((tick, Event::Pause(player, paused)));
+      paused
+    });
+    if let Some(paused) = pause {
+      self.paused = paused;
+    }
+    for i in 0..self.boards.len() {
+      if let Some(snapshot) = self.resyncs[i].remove(&tick) {
+        self.boards[i].restore(&snapshot);
+      }
+
+      let actions = self.inputs[i].remove(&tick).unwrap_or_default();
+      let board = &mut self.boards[i];
+      if let Some(left) = self.left[i] {
+        if left <= tick {
+          board.state = board::GameState::Over;
+        }
+      }
+      if let Some(paused) = pause {
+        board.pause(paused);
+      }
+      for &action in actions.iter() {
+        if board.state == board::GameState::Playing {
+          board.apply(action);
+          self.record.events.push((tick, Event::Input(i, action)));
+        }
+      }
+      board.tick();
+      self.history[i].insert(tick, Record { actions: actions, garbage: Vec::new(), pause: pause });
+    }
+
+    // everybody sends their garbage the same way, so nobody has to be told about it
+    for i in 0..self.boards.len() {
+      let attack = self.boards[i].take_attack();
+      if attack == 0 {
+        continue
+      }
+      if let Some(target) = board::garbage_target(&self.boards, i) {
+        let hole_col = self.rng.gen_range(0, BOARD_WIDTH as usize);
+        self.boards[target].queue_garbage(attack, hole_col);
+        if let Some(record) = self.history[target].get_mut(&tick) {
+          record.garbage.push((attack, hole_col));
+        }
+      }
+    }
+    self.tick += 1;
+
+    if self.tick % CHECKSUM_INTERVAL == 0 {
+      for i in 0..self.boards.len() {
+        let ours = self.boards[i].checksum();
+        self.checksums[i].insert(self.tick, ours);
+        if let Some(theirs) = self.reported[i].remove(&self.tick) {
+          self.check(i, ours, theirs);
+        }
+      }
+    }
+
+    // forget anything too old to resync from
+    if self.tick > HISTORY_TICKS {
+      let oldest = self.tick - HISTORY_TICKS;
+      for i in 0..self.boards.len() {
+        self.history[i] = self.history[i].split_off(&oldest);
+        self.checksums[i] = self.checksums[i].split_off(&oldest);
+      }
+    }
+  }
+}
@@ -5,8 +5,12 @@ extern crate glutin_window;
 extern crate opengl_graphics;
 extern crate rand;
 extern crate websocket;
+extern crate tetris;
 
+use std::cmp;
 use std::thread;
+use std::time::Duration;
+use std::sync::mpsc;
 use std::sync::mpsc::channel;
 use websocket::{Message, Sender, Receiver};
 use websocket::message::Type;
@@ -20,43 +24,235 @@ use opengl_graphics::{ GlGraphics, OpenGL };
 use opengl_graphics::glyph_cache::GlyphCache;
 use graphics::*;
 
-use std::path::Path;
-use std::fs::OpenOptions;
-use board::Board;
-use block::TETROMINOS;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use tetris::board;
+use tetris::board::Action;
+use tetris::board::Cell;
+use tetris::board::Snapshot;
+use tetris::config;
+use tetris::config::Config;
+use tetris::lockstep;
+use tetris::lockstep::Lockstep;
+use tetris::block::Result;
+use tetris::protocol;
+use tetris::protocol::{ClientMessage, Frame, LobbyPlayer, ServerMessage};
+use tetris::replay;
+use tetris::replay::{Playback, Replay};
+use tetris::{BOARD_WIDTH, BOARD_HEIGHT, TICKS_PER_SECOND};
 
+// reconnecting backs off from MIN_BACKOFF_MS up to MAX_BACKOFF_MS between tries, and
+// gives up after RECONNECT_ATTEMPTS, around when the server stops holding our place
+const RECONNECT_ATTEMPTS: u32 = 8;
+const MIN_BACKOFF_MS: u64 = 250;
+const MAX_BACKOFF_MS: u64 = 8000;
 
-mod block;
-mod board;
+// text and margins were picked for blocks this big, and scale with the blocks
+pub const BLOCK_SIZE: i64 = config::DEFAULT_BLOCK_SIZE;
 
-pub const BLOCK_SIZE: i64 = 30;
-pub const BOARD_WIDTH: i64 = 10; // 10 cells across
-pub const BOARD_HEIGHT: i64 = 22; // 22 cells up n down
+const BOARDS_PER_ROW: usize = 4;
 
-pub const NUM_BOARDS: i64 = 2; // number of boards
+const PANEL_WIDTH: i64 = 5; // cells to the right of each board for the hold piece
+const BOARD_SLOT_WIDTH: i64 = BOARD_WIDTH + PANEL_WIDTH; // board + its panel
+const NAME_HEIGHT: i64 = 1; // cells above each board for the player's name
+const BOARD_SLOT_HEIGHT: i64 = BOARD_HEIGHT + NAME_HEIGHT;
+
+const OPENGL: OpenGL = OpenGL::V3_2;
 
 pub const FONT_SIZE: u32 = 24;
 const SCORE_LEFT_MARGIN: f64 = 15.0;
 const SCORE_TOP_MARGIN: f64 = 35.0;
 
+const HOLD_TOP_MARGIN: f64 = 2.0; // in cells
+const LEVEL_TOP_MARGIN: f64 = 250.0;
+const GARBAGE_METER_WIDTH: f64 = 6.0; // in pixels
+
 const GAMEOVER_LEFT_MARGIN: f64 = 70.0;
 const GAMEOVER_TOP_MARGIN: f64 = 250.0;
 
+const TITLE_FONT_SIZE: u32 = 48;
+const START_LEFT_MARGIN: f64 = 40.0;
+const START_TOP_MARGIN: f64 = 120.0;
+const CONTROLS_LEFT_MARGIN: f64 = 170.0; // where the key names line up
+const LINE_HEIGHT: f64 = 34.0;
+const NAME_BOTTOM_MARGIN: f64 = 8.0; // between a player's name and the top of their board
+const PLAYBACK_BOTTOM_MARGIN: f64 = 90.0; // playback status, up from the bottom of the panel
+
+// seeking in a replay jumps this far
+const SEEK_SECONDS: u64 = 5;
+
+// what's on the start screen, (key, what it does)
+const CONTROLS: [(&str, &str); 8] = [
+  ("left right", "move"),
+  ("down", "soft drop"),
+  ("space", "hard drop"),
+  ("up  x", "rotate"),
+  ("z", "rotate back"),
+  ("c", "hold"),
+  ("p", "pause"),
+  ("esc", "quit")
+];
+
 const BOARD_BKD_COLOR: [f32; 4] = [0.18, 0.18, 0.18, 1.0]; // dark gray
-// const RED: [f32; 4] = [0.96, 0.12, 0.12, 1.0];
+const RED: [f32; 4] = [0.96, 0.12, 0.12, 1.0];
 const BLACK: [f32; 4] = [0.0, 0.0, 0.0, 1.0];
 const WHITE: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+const GRAY: [f32; 4] = [0.7, 0.7, 0.7, 1.0];
+const GARBAGE_COLOR: [f32; 4] = [0.5, 0.5, 0.5, 1.0]; // gray
 // const BRIGHT_GREEN: [f32; 4] = [0.0, 1.0, 0.0, 1.0];
 
+// where each board goes in the window. boards go in rows of up to BOARDS_PER_ROW,
+// and get drawn at 2/3 size once there's more than one row
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+  columns: usize,
+  rows: usize,
+  block_size: f64 // in pixels
+}
+
+impl Layout {
+  fn new(num_boards: usize, block_size: i64) -> Layout {
+    let columns = num_boards.clamp(1, BOARDS_PER_ROW);
+    let rows = (num_boards + columns - 1) / columns;
+    let block_size = if rows > 1 { block_size * 2 / 3 } else { block_size };
+    Layout {
+      columns: columns,
+      rows: rows.max(1),
+      block_size: block_size as f64
+    }
+  }
+
+  fn window_size(&self) -> [u32; 2] {
+    [(self.block_size * (BOARD_SLOT_WIDTH * self.columns as i64) as f64) as u32,
+     (self.block_size * (BOARD_SLOT_HEIGHT * self.rows as i64) as f64) as u32]
+  }
+
+  // top left corner of board i, in pixels
+  fn board_origin(&self, i: usize) -> (f64, f64) {
+    let col = (i % self.columns) as i64;
+    let row = (i / self.columns) as i64;
+    (self.block_size * (BOARD_SLOT_WIDTH * col) as f64,
+     self.block_size * (BOARD_SLOT_HEIGHT * row + NAME_HEIGHT) as f64)
+  }
+
+  // text and margins were picked for BLOCK_SIZE, so they grow and shrink with the blocks
+  fn scale(&self) -> f64 {
+    self.block_size / BLOCK_SIZE as f64
+  }
+}
+
 pub struct App {
   gl: GlGraphics, // OpenGL drawing backend.
-  boards: Vec<board::Board>, // game boards
+  game: Lockstep, // game boards
+  tick: u64, // ticks of our own clock so far. the boards can lag behind this
+  layout: Layout,
   cache: GlyphCache<'static>, // for drawing text
   token: i32,
-  winner: i32
+  winner: i32,
+  authoritative: bool, // the server runs the boards, we just draw them
+  started: bool, // false while we're showing the start screen or the lobby
+  lobby: Option<Lobby>, // None when we're playing offline
+  names: Vec<String>, // what to call each board's player
+  watching: bool, // spectating or playing back a replay, nobody's at the keys
+  wins: Vec<u32>, // rounds each player has won this match, once one's over
+  match_over: bool, // the last round settled the match, waiting to hear about a rematch
+  round: u32 // rounds we've played since the window opened, counting this one
+}
+
+// who's in the game before it starts
+struct Lobby {
+  players: Vec<LobbyPlayer>,
+  countdown: Option<u8>, // seconds until START!, once everybody's ready
+  ready: bool // whether we've said we're ready
 }
 
 impl App {
+  fn new(layout: Layout, cache: GlyphCache<'static>, num_players: usize, seed: u64, token: i32,
+         authoritative: bool) -> App {
+    App {
+      gl: GlGraphics::new(OPENGL),
+      game: Lockstep::new(num_players, seed),
+      tick: 0,
+      layout: layout,
+      cache: cache,
+      token: token,
+      winner: -1,
+      authoritative: authoritative,
+      started: true,
+      lobby: None,
+      names: vec![String::new(); num_players],
+      watching: false,
+      wins: Vec::new(),
+      match_over: false,
+      round: 0
+    }
+  }
+
+  // the lobby's done, on to the game
+  fn start(&mut self, seed: u64, authoritative: bool) {
+    if let Some(ref lobby) = self.lobby {
+      for player in lobby.players.iter() {
+        if let Ok(i) = protocol::board_index(player.token, self.names.len()) {
+          self.names[i] = player.name.clone();
+        }
+      }
+    }
+    self.authoritative = authoritative;
+    self.wins.clear();
+    // a new match, so everybody who left the last one is back in
+    self.game = Lockstep::new(self.game.boards.len(), seed);
+    self.begin_round();
+  }
+
+  fn handle_lobby(&mut self, players: Vec<LobbyPlayer>) {
+    let token = self.token;
+    if let Some(ref mut lobby) = self.lobby {
+      // whatever changed, the countdown starts over
+      lobby.countdown = None;
+      if let Some(me) = players.iter().find(|p| p.token == token) {
+        lobby.ready = me.ready;
+      }
+      lobby.players = players;
+    }
+  }
+
+  // start over on the same boards, with new pieces
+  fn restart(&mut self, seed: u64) {
+    self.game.reset(seed);
+    self.begin_round();
+  }
+
+  fn begin_round(&mut self) {
+    self.round += 1;
+    self.tick = 0;
+    self.winner = -1;
+    self.started = true;
+    self.match_over = false;
+    if let Some(ref mut lobby) = self.lobby {
+      lobby.countdown = None;
+    }
+  }
+
+  // the server's called the round. a match that's over goes back to the lobby once
+  // we've seen how it ended
+  fn handle_round_over(&mut self, winner: i32, match_over: bool, wins: Vec<u32>) -> Result<()> {
+    if wins.len() != self.game.boards.len() {
+      return Err(format!("round results for {} players in a game of {}", wins.len(), self.game.boards.len()));
+    }
+    let tally: Vec<String> = wins.iter().map(|w| w.to_string()).collect();
+    println!("Player {} wins the {} ({})", winner, if match_over { "match" } else { "round" }, tally.join("-"));
+    self.winner = winner;
+    self.wins = wins;
+    self.match_over = match_over;
+    // spectators don't get a say in the rematch
+    if match_over && self.watching {
+      self.started = false;
+    }
+    Ok(())
+  }
+
   fn render(&mut self, args: &RenderArgs) {
 
     self.gl.draw(args.viewport(), |_, gl| {
@@ -64,49 +260,116 @@ impl App {
       clear(BOARD_BKD_COLOR, gl);
     });
 
-    for (i, board) in &mut self.boards.iter().enumerate() {
-      // do nothing if paused
-      if board.state == board::GameState::Paused {
-        continue
+    if !self.started {
+      if self.lobby.is_some() {
+        self.render_lobby(args);
+      } else {
+        self.render_start_screen(args);
       }
+      return
+    }
+
+    let size = self.layout.block_size;
+    let scale = self.layout.scale();
+    let font_size = (FONT_SIZE as f64 * scale) as u32;
+    let num_boards = self.game.boards.len();
+    let watching = self.watching;
+    let winner = self.winner;
+    let match_over = self.match_over;
+    let countdown = self.lobby.as_ref().and_then(|l| l.countdown);
 
+    // seconds until paused boards go again, if somebody's asked
+    let resuming = if self.authoritative {
+      countdown
+    } else {
+      self.game.resuming_in().map(|ticks| ((ticks + TICKS_PER_SECOND - 1) / TICKS_PER_SECOND) as u8)
+    };
+
+    for (i, board) in &mut self.game.boards.iter().enumerate() {
       let font_cache = &mut self.cache;
+      let (left, top) = self.layout.board_origin(i);
 
-      // show game over screen if game is done
-      if board.state == board::GameState::Over {
+      // whose board it is goes just above it, with how many rounds they've won
+      let name = match self.wins.get(i) {
+        Some(wins) => format!("{}  {}", self.names[i], wins),
+        None => self.names[i].clone()
+      };
+      let name_color = if i as i32 == self.token - 1 { WHITE } else { GRAY };
+      if !name.is_empty() {
         self.gl.draw(args.viewport(), |c, gl| {
+          let mut text = graphics::Text::new(font_size);
+          text.color = name_color;
+          let transform = c.trans(left + SCORE_LEFT_MARGIN * scale, top - NAME_BOTTOM_MARGIN * scale);
+          text.draw(&name,
+                font_cache,
+                &c.draw_state,
+                transform.transform,
+                gl);
+        });
+      }
+
+      // show game over screen if game is done, or the round is. paused boards get
+      // covered up the same way
+      let won = winner == i as i32 + 1;
+      let paused = board.state == board::GameState::Paused;
+      if board.state != board::GameState::Playing || winner != -1 {
+        self.gl.draw(args.viewport(), |c, gl| {
+          let c = c.trans(left, top);
           rectangle(BLACK,
-               [(BOARD_WIDTH * BLOCK_SIZE) as f64 * i as f64,
+               [0.0,
                 0.0,
-                (BOARD_WIDTH * BLOCK_SIZE) as f64,
-                (BOARD_HEIGHT * BLOCK_SIZE) as f64],
-               c.transform.trans(0.0, 0.0),
+                BOARD_WIDTH as f64 * size,
+                BOARD_HEIGHT as f64 * size],
+               c.transform,
                gl);
 
           // draw game over message
-          let mut text = graphics::Text::new(FONT_SIZE);
+          let mut text = graphics::Text::new(font_size);
           text.color = WHITE;
           let mut transform: graphics::context::Context =
-                c.trans(GAMEOVER_LEFT_MARGIN + (BLOCK_SIZE * BOARD_WIDTH * i as i64) as f64, GAMEOVER_TOP_MARGIN);
-          text.draw(&format!("GAME OVER"),
+                c.trans(GAMEOVER_LEFT_MARGIN * scale, GAMEOVER_TOP_MARGIN * scale);
+          let title = match (won, paused) {
+            (true, _) => "  WINNER",
+            (false, true) => "  PAUSED",
+            (false, false) => "GAME OVER"
+          };
+          text.draw(title,
                 font_cache,
                 &c.draw_state,
                 transform.transform,
                 gl);
-          transform = transform.trans(0.0, FONT_SIZE as f64 + 40.0);
+          transform = transform.trans(0.0, (FONT_SIZE as f64 + 40.0) * scale);
           // not centered because i am a weenie
           text.draw(&format!("   Score: {}", board.score),
                 font_cache,
                 &c.draw_state,
                 transform.transform,
                 gl);
+          // what happens next. by yourself you can just go again
+          let next = match (countdown, resuming) {
+            (Some(seconds), _) if winner != -1 => format!("next round in {}", seconds),
+            (_, Some(seconds)) if paused => format!("  resuming in {}", seconds),
+            _ if watching => String::new(),
+            _ if paused => "     P to resume".to_string(),
+            _ if match_over => "ENTER for a rematch".to_string(),
+            _ if num_boards == 1 => "ENTER to play again".to_string(),
+            _ => String::new()
+          };
+          if !next.is_empty() {
+            transform = transform.trans(-GAMEOVER_LEFT_MARGIN * scale / 2.0, (FONT_SIZE as f64 + 40.0) * scale);
+            text.draw(&next,
+                  font_cache,
+                  &c.draw_state,
+                  transform.transform,
+                  gl);
+          }
 
           // draw border
           let rect_border = graphics::Rectangle::new_border(WHITE, 0.3);
-          rect_border.draw([(BOARD_WIDTH * BLOCK_SIZE) as f64 * i as f64,
+          rect_border.draw([0.0,
                    0.0,
-                   (BOARD_WIDTH * BLOCK_SIZE) as f64,
-                   (BOARD_HEIGHT * BLOCK_SIZE) as f64],
+                   BOARD_WIDTH as f64 * size,
+                   BOARD_HEIGHT as f64 * size],
             &c.draw_state,
             c.transform,
             gl);
@@ -128,17 +391,27 @@ impl App {
       let ghost_y = board.ghost_piece.y_offset;
       let ghost_piece_color = board.ghost_piece.color;
 
+      let hold_piece = board.hold_piece;
+      let pending_garbage = board.pending_garbage_lines();
+      let level = board.level;
+      let lines_cleared = board.lines_cleared;
+      let last_clear = board.last_clear;
+
       self.gl.draw(args.viewport(), |c, gl| {
+        let c = c.trans(left, top);
+
         // iterate thru board cells and draw in filled-in blocks
         for row in 0..cells.len() {
           for col in 0..cells[0].len() {
-            if cells[row][col] == 1 {
-              let x: f64 = col as f64 + (BOARD_WIDTH * i as i64) as f64;
-              let y: f64 = row as f64;
-              let size: f64 = BLOCK_SIZE as f64;
-              let b = rectangle::square(x * size, y * size, size);
-              rectangle(WHITE, b, c.transform.trans(0.0, 0.0), gl);
-            }
+            let color = match cells[row][col] {
+              Cell::Block(shape) => shape.color(),
+              Cell::Garbage => GARBAGE_COLOR,
+              Cell::Empty => continue
+            };
+            let x: f64 = col as f64;
+            let y: f64 = row as f64;
+            let b = rectangle::square(x * size, y * size, size);
+            rectangle(color, b, c.transform, gl);
           }
         }
 
@@ -146,11 +419,10 @@ impl App {
         for row in 0..blocks.len() {
           for col in 0..blocks[0].len() {
             if blocks[row][col] == 1 {
-              let x: f64 = (col as f64) + current_x + (BOARD_WIDTH * i as i64) as f64;
+              let x: f64 = (col as f64) + current_x;
               let y: f64 = (row as f64) + current_y;
-              let size: f64 = BLOCK_SIZE as f64;
               let b = rectangle::square(x * size, y * size, size);
-              rectangle(piece_color, b, c.transform.trans(0.0, 0.0), gl);
+              rectangle(piece_color, b, c.transform, gl);
             }
           }
         }
@@ -159,32 +431,94 @@ impl App {
         for row in 0..ghost_blocks.len() {
           for col in 0..ghost_blocks[0].len() {
             if ghost_blocks[row][col] == 1 {
-              let x: f64 = (col as f64) + ghost_x + (BOARD_WIDTH * i as i64) as f64;
+              let x: f64 = (col as f64) + ghost_x;
               let y: f64 = (row as f64) + ghost_y;
-              let size: f64 = BLOCK_SIZE as f64;
               let b = rectangle::square(x * size, y * size, size);
-              rectangle(ghost_piece_color, b, c.transform.trans(0.0, 0.0), gl);
+              rectangle(ghost_piece_color, b, c.transform, gl);
             }
           }
         }
 
         // draw score
-        let mut text = graphics::Text::new(FONT_SIZE);
+        let mut text = graphics::Text::new(font_size);
         text.color = WHITE;
         let transform: graphics::context::Context =
-              c.trans(SCORE_LEFT_MARGIN + (BLOCK_SIZE as f64) * (BOARD_WIDTH * i as i64) as f64, SCORE_TOP_MARGIN);
+              c.trans(SCORE_LEFT_MARGIN * scale, SCORE_TOP_MARGIN * scale);
         text.draw(&format!("Score: {}", board.score),
               font_cache,
               &c.draw_state,
               transform.transform,
               gl);
 
+        // incoming garbage meter along the right edge of the board
+        if pending_garbage > 0 {
+          let height = (pending_garbage as i64).min(BOARD_HEIGHT) as f64 * size;
+          rectangle(RED,
+               [BOARD_WIDTH as f64 * size,
+                BOARD_HEIGHT as f64 * size - height,
+                GARBAGE_METER_WIDTH * scale,
+                height],
+               c.transform,
+               gl);
+        }
+
+        // draw hold piece in the panel next to the board
+        let panel = c.trans(BOARD_WIDTH as f64 * size, 0.0);
+        let transform: graphics::context::Context =
+              panel.trans(SCORE_LEFT_MARGIN * scale, SCORE_TOP_MARGIN * scale);
+        text.draw("Hold",
+              font_cache,
+              &c.draw_state,
+              transform.transform,
+              gl);
+        let transform: graphics::context::Context =
+              panel.trans(SCORE_LEFT_MARGIN * scale, LEVEL_TOP_MARGIN * scale);
+        text.draw(&format!("Level {}", level),
+              font_cache,
+              &c.draw_state,
+              transform.transform,
+              gl);
+        let transform = transform.trans(0.0, (FONT_SIZE as f64 + 10.0) * scale);
+        text.draw(&format!("Lines {}", lines_cleared),
+              font_cache,
+              &c.draw_state,
+              transform.transform,
+              gl);
+        // what the last piece did, a word a line so it fits in the panel
+        if let Some(clear) = last_clear {
+          let mut transform = transform.trans(0.0, (FONT_SIZE as f64 + 10.0) * scale);
+          let mut words: Vec<String> = clear.name().split(' ').map(|w| w.to_string()).collect();
+          if clear.combo > 1 {
+            words.push(format!("COMBO {}", clear.combo - 1));
+          }
+          for word in words.iter() {
+            transform = transform.trans(0.0, (FONT_SIZE as f64 + 10.0) * scale);
+            text.draw(word,
+                  font_cache,
+                  &c.draw_state,
+                  transform.transform,
+                  gl);
+          }
+        }
+        if let Some(piece) = hold_piece {
+          for row in 0..piece.blocks.len() {
+            for col in 0..piece.blocks[0].len() {
+              if piece.blocks[row][col] == 1 {
+                let x: f64 = (col as f64) + 0.5;
+                let y: f64 = (row as f64) + HOLD_TOP_MARGIN;
+                let b = rectangle::square(x * size, y * size, size);
+                rectangle(piece.color, b, panel.transform, gl);
+              }
+            }
+          }
+        }
+
         // draw border
         let rect_border = graphics::Rectangle::new_border(WHITE, 0.3);
-        rect_border.draw([(BOARD_WIDTH * BLOCK_SIZE) as f64 * i as f64,
+        rect_border.draw([0.0,
                  0.0,
-                 (BOARD_WIDTH * BLOCK_SIZE) as f64,
-                 (BOARD_HEIGHT * BLOCK_SIZE) as f64],
+                 BOARD_WIDTH as f64 * size,
+                 BOARD_HEIGHT as f64 * size],
           &c.draw_state,
           c.transform,
           gl);
@@ -193,246 +527,841 @@ impl App {
     }
   }
 
-  fn update(&mut self) {
-    for mut board in &mut self.boards {
-      if board.state != board::GameState::Playing {
-        continue
+  fn render_start_screen(&mut self, args: &RenderArgs) {
+    let scale = self.layout.scale();
+    let font_size = (FONT_SIZE as f64 * scale) as u32;
+    let font_cache = &mut self.cache;
+
+    self.gl.draw(args.viewport(), |c, gl| {
+      let mut title = graphics::Text::new((TITLE_FONT_SIZE as f64 * scale) as u32);
+      title.color = WHITE;
+      let transform = c.trans(START_LEFT_MARGIN * scale, START_TOP_MARGIN * scale);
+      title.draw("t e t r i s",
+            font_cache,
+            &c.draw_state,
+            transform.transform,
+            gl);
+
+      let mut text = graphics::Text::new(font_size);
+      text.color = WHITE;
+      let mut transform = transform.trans(0.0, 2.0 * LINE_HEIGHT * scale);
+      text.draw("press ENTER to start",
+            font_cache,
+            &c.draw_state,
+            transform.transform,
+            gl);
+
+      transform = transform.trans(0.0, LINE_HEIGHT * scale);
+      for &(keys, action) in CONTROLS.iter() {
+        transform = transform.trans(0.0, LINE_HEIGHT * scale);
+        text.draw(keys,
+              font_cache,
+              &c.draw_state,
+              transform.transform,
+              gl);
+        text.draw(action,
+              font_cache,
+              &c.draw_state,
+              transform.trans(CONTROLS_LEFT_MARGIN * scale, 0.0).transform,
+              gl);
       }
-      board.advance_board();
-    }
+    });
   }
-}
 
-fn main() {
-  let url = Url::parse("ws://127.0.0.1:3012").unwrap();
-  println!("Connecting to {}", url);
+  fn render_lobby(&mut self, args: &RenderArgs) {
+    let scale = self.layout.scale();
+    let font_size = (FONT_SIZE as f64 * scale) as u32;
+    let font_cache = &mut self.cache;
+    let num_players = self.game.boards.len();
+    let token = self.token;
+    let watching = self.watching;
+    let wins = self.wins.clone();
+    let lobby = match self.lobby {
+      Some(ref lobby) => lobby,
+      None => return
+    };
 
-  let request = Client::connect(url).unwrap();
-  let response = request.send().unwrap(); // Send the request and retrieve a response
-  println!("Validating response...");
+    self.gl.draw(args.viewport(), |c, gl| {
+      let mut title = graphics::Text::new((TITLE_FONT_SIZE as f64 * scale) as u32);
+      title.color = WHITE;
+      let transform = c.trans(START_LEFT_MARGIN * scale, START_TOP_MARGIN * scale);
+      title.draw("lobby",
+            font_cache,
+            &c.draw_state,
+            transform.transform,
+            gl);
 
-  response.validate().unwrap(); // Validate the response
-  println!("Successfully connected");
+      let mut text = graphics::Text::new(font_size);
+      let mut transform = transform.trans(0.0, LINE_HEIGHT * scale);
+      // one line per place in the game, filled or not
+      for t in 1..(num_players as i32 + 1) {
+        transform = transform.trans(0.0, LINE_HEIGHT * scale);
+        let (name, status) = match lobby.players.iter().find(|p| p.token == t) {
+          Some(p) => (p.name.as_str(), if p.ready { "ready" } else { "not ready" }),
+          None => ("...", "waiting")
+        };
+        text.color = if t == token { WHITE } else { GRAY };
+        // how the last match went
+        let name = match wins.get((t - 1) as usize) {
+          Some(n) => format!("{}. {}  ({})", t, name, n),
+          None => format!("{}. {}", t, name)
+        };
+        text.draw(&name,
+              font_cache,
+              &c.draw_state,
+              transform.transform,
+              gl);
+        text.draw(status,
+              font_cache,
+              &c.draw_state,
+              transform.trans(CONTROLS_LEFT_MARGIN * scale * 1.5, 0.0).transform,
+              gl);
+      }
 
-  let mut token = 0;
-  let (mut sender, mut receiver) = response.begin().split();
-  let (tx, rx) = channel();
+      text.color = WHITE;
+      transform = transform.trans(0.0, 2.0 * LINE_HEIGHT * scale);
+      let footer = match (lobby.countdown, lobby.ready) {
+        (Some(seconds), _) => format!("starting in {}", seconds),
+        (None, _) if watching => "spectating".to_string(),
+        (None, true) => "waiting for everyone else".to_string(),
+        (None, false) if !wins.is_empty() => "press ENTER for a rematch".to_string(),
+        (None, false) => "press ENTER when you're ready".to_string()
+      };
+      text.draw(&footer,
+            font_cache,
+            &c.draw_state,
+            transform.transform,
+            gl);
+    });
+  }
 
-  let tx_1 = tx.clone();
-  let tx_2 = tx.clone();
+  // status of a replay being watched, at the bottom of the first board's panel
+  fn render_playback(&mut self, args: &RenderArgs, playback: &Playback) {
+    let size = self.layout.block_size;
+    let scale = self.layout.scale();
+    let font_size = (FONT_SIZE as f64 * scale) as u32;
+    let font_cache = &mut self.cache;
+    let (left, top) = self.layout.board_origin(0);
+    let lines = [
+      format!("{} / {}", clock(playback.tick()), clock(playback.replay.length)),
+      format!("x{}", playback.speed),
+      if playback.paused { "paused".to_string() } else { String::new() }
+    ];
 
-  let send_loop = thread::spawn(move || {
-    loop {
-      // Send loop
-      let message: Message = match rx.recv() {
-        Ok(m) => m,
-        Err(e) => {
-          println!("Error: Send Loop: {:?}", e);
-          return;
-        }
-      };
-      match message.opcode {
-        Type::Close => {
-          let _ = sender.send_message(&message);
-          // If it's a close message, just send it and then return.
-          return;
-        },
-        _ => (),
+    self.gl.draw(args.viewport(), |c, gl| {
+      let mut text = graphics::Text::new(font_size);
+      text.color = GRAY;
+      let mut transform = c.trans(left + BOARD_WIDTH as f64 * size + SCORE_LEFT_MARGIN * scale,
+                                  top + BOARD_HEIGHT as f64 * size - PLAYBACK_BOTTOM_MARGIN * scale);
+      for line in lines.iter() {
+        text.draw(line,
+              font_cache,
+              &c.draw_state,
+              transform.transform,
+              gl);
+        transform = transform.trans(0.0, (FONT_SIZE as f64 + 10.0) * scale);
       }
-      // Send the message
-      match sender.send_message(&message) {
-        Ok(()) => (),
-        Err(e) => {
-          println!("Error: Send Loop: {:?}", e);
-          let _ = sender.send_message(&Message::close());
-          return;
-        }
+    });
+  }
+
+  // the game so far, names and all
+  fn replay(&self) -> Replay {
+    let mut replay = self.game.replay();
+    replay.names = self.names.clone();
+    replay
+  }
+
+  // our own key press. it happens a few ticks from now, on every client at once.
+  // returns the message telling everyone else about it
+  fn press(&mut self, action: Action) -> ClientMessage {
+    let tick = self.tick + lockstep::INPUT_DELAY;
+    if !self.authoritative {
+      if let Err(e) = self.game.add_input((self.token - 1) as usize, tick, action) {
+        println!("Error: Main Loop: {}", e);
       }
     }
-  });
+    ClientMessage::Input(self.token, tick, action)
+  }
 
-  let (tx1, rx1) = channel();
-  let tx1_1 = tx1.clone();
-  let receive_loop = thread::spawn(move || {
-    // Receive loop
-    for message in receiver.incoming_messages() {
-      let message: Message = match message {
-        Ok(m) => m,
-        Err(e) => {
-          println!("Error: Receive Loop: {:?}", e);
-          let _ = tx_1.send(Message::close());
-          return;
+  // pause every board, or start them going again after a countdown. goes out to
+  // everyone like a key press, so all the boards stop on the same tick
+  fn toggle_pause(&mut self) -> ClientMessage {
+    let paused = if self.authoritative {
+      self.game.boards.iter().any(|b| b.state == board::GameState::Paused)
+    } else {
+      self.game.paused()
+    };
+    let mut tick = self.tick + lockstep::INPUT_DELAY;
+    if paused {
+      tick += lockstep::RESUME_COUNTDOWN;
+    }
+    if !self.authoritative {
+      if let Err(e) = self.game.pause((self.token - 1) as usize, tick, !paused) {
+        println!("Error: Main Loop: {}", e);
+      }
+    }
+    ClientMessage::Pause(self.token, tick, !paused)
+  }
+
+  // an authoritative server sends us whole boards instead of inputs
+  fn handle_state(&mut self, token: i32, snapshot: &Snapshot) -> Result<()> {
+    let i = protocol::board_index(token, self.game.boards.len())?;
+    self.game.boards[i].restore(snapshot);
+    Ok(())
+  }
+
+  // somebody dropped and didn't come back
+  fn handle_left(&mut self, token: i32, tick: u64) -> Result<()> {
+    let i = protocol::board_index(token, self.game.boards.len())?;
+    println!("Player {} left the game", token);
+    self.game.remove_player(i, tick);
+    Ok(())
+  }
+
+  // hand another player's message to the lockstep sim. returns what to send back, if
+  // anything
+  fn handle_relay(&mut self, message: ClientMessage) -> Result<Option<ClientMessage>> {
+    let num_players = self.game.boards.len();
+    let own = (self.token - 1) as usize;
+    match message {
+      ClientMessage::Input(token, tick, action) => {
+        let i = protocol::board_index(token, num_players)?;
+        if token != self.token {
+          self.game.add_input(i, tick, action)?;
         }
-      };
-      match message.opcode {
-        Type::Close => {
-          // Got a close message, so send a close message and return
-          let _ = tx_1.send(Message::close());
-          return;
+      },
+      ClientMessage::Confirm(token, tick) => {
+        let i = protocol::board_index(token, num_players)?;
+        if token != self.token {
+          self.game.confirm(i, tick);
         }
-        Type::Ping => match tx_1.send(Message::pong(message.payload)) {
-          // Send a pong in response
-          Ok(()) => (),
-          Err(e) => {
-            println!("Error: Receive Loop: {:?}", e);
-            return;
+      },
+      ClientMessage::Garbage { .. } => {
+        // every client works out the garbage in its own sim, so there's nothing to do
+      },
+      ClientMessage::GameOver(winner) => {
+        let i = protocol::board_index(winner, num_players)?;
+        // everybody's last confirm comes before this, so a spectator can play the
+        // boards out to where the players saw them end before the round gets saved
+        if self.watching {
+          self.game.advance();
+        }
+        self.winner = winner;
+        println!("==== GAME OVER ====\n WINNER: PLAYER {}, SCORE: {}", winner, self.game.boards[i].score);
+      },
+      ClientMessage::Checksum(token, tick, checksum) => {
+        let i = protocol::board_index(token, num_players)?;
+        if token != self.token {
+          self.game.report_checksum(i, tick, checksum);
+        }
+      },
+      ClientMessage::Desync(token, board) => {
+        // somebody's copy of our board is off. send them ours
+        let i = protocol::board_index(board, num_players)?;
+        if i == own {
+          println!("Player {} is out of sync with us, resyncing", token);
+          let snapshot = Box::new(self.game.boards[own].snapshot());
+          return Ok(Some(ClientMessage::Resync(self.token, self.game.tick, snapshot)));
+        }
+      },
+      ClientMessage::Resync(token, tick, snapshot) => {
+        let i = protocol::board_index(token, num_players)?;
+        if token != self.token {
+          self.game.resync(i, tick, &snapshot)?;
+        }
+      },
+      ClientMessage::Pause(token, tick, paused) => {
+        if self.authoritative {
+          // the server pauses the boards itself. any countdown we've got is from
+          // the last time they were resumed
+          if let (true, Some(lobby)) = (paused, self.lobby.as_mut()) {
+            lobby.countdown = None;
+          }
+        } else {
+          let i = protocol::board_index(token, num_players)?;
+          if token != self.token {
+            self.game.pause(i, tick, paused)?;
           }
-        },
-        // Pass along to games the message we received
-        _ => {
-          let message1 = String::from_utf8(message.payload.into_owned()).unwrap();
-          tx1_1.send(Message::text(message1)).unwrap(); 
         }
+      },
+      ClientMessage::Ack { .. } | ClientMessage::Rejoin { .. } | ClientMessage::Ready(..) |
+      ClientMessage::LegacyInput(..) | ClientMessage::FirstBlock(..) | ClientMessage::NewBlock(..) => {
+        return Err(format!("unexpected message: {}", message));
+      }
+    }
+    Ok(None)
+  }
+
+  // one tick of our clock. we've sent every input we're going to for the ticks before
+  // the one we confirm, so the boards can catch up to it once everyone else has too.
+  // returns the messages that need to go out
+  fn step(&mut self) -> Vec<ClientMessage> {
+    self.tick += 1;
+    // spectators have no board of their own, just everyone else's
+    if self.watching {
+      self.game.advance();
+      return Vec::new();
+    }
+    let confirmed = self.tick + lockstep::INPUT_DELAY;
+    let own = (self.token - 1) as usize;
+    self.game.confirm(own, confirmed);
+    let start = self.game.tick;
+    self.game.advance();
+
+    let mut messages = vec![ClientMessage::Confirm(self.token, confirmed)];
+    // let everyone check their copy of our board
+    for tick in (start + 1)..(self.game.tick + 1) {
+      if let Some(checksum) = self.game.checksum(own, tick) {
+        messages.push(ClientMessage::Checksum(self.token, tick, checksum));
       }
     }
-  });
-  let opengl = OpenGL::V3_2;
+    for board in self.game.take_desyncs() {
+      println!("Player {}'s board is out of sync, asking for it again", board + 1);
+      messages.push(ClientMessage::Desync(self.token, board as i32 + 1));
+    }
+    messages
+  }
+}
+
+// keyboard controls for the local player's board
+fn action_for_key(key: Key) -> Option<Action> {
+  match key {
+    Key::Left => Some(Action::MoveLeft),
+    Key::Right => Some(Action::MoveRight),
+    Key::Down => Some(Action::SoftDrop),
+    Key::Space => Some(Action::HardDrop),
+    Key::Up | Key::X => Some(Action::RotateCw),
+    Key::Z => Some(Action::RotateCcw),
+    Key::C => Some(Action::Hold),
+    _ => None
+  }
+}
 
-  // Create an Glutin window.
-  let mut window: Window = WindowSettings::new(
-      "t e t r i s",
-      [(BLOCK_SIZE * BOARD_WIDTH * NUM_BOARDS) as u32, (BLOCK_SIZE * BOARD_HEIGHT) as u32]
-    )
-    .opengl(opengl)
+// the window's size depends on how many boards there are, so each game gets its own
+fn open_window(layout: &Layout) -> Window {
+  match WindowSettings::new("t e t r i s", layout.window_size())
+    .opengl(OPENGL)
     .exit_on_esc(true)
-    .build()
-    .unwrap();
-
-  let font_path = match OpenOptions::new().read(true).open("Lato-Light.ttf") {
-    Ok(_) => Path::new("Lato-Light.ttf"),
-    Err(_) => {
-      match OpenOptions::new().read(true).open("src/Lato-Light.ttf") {
-        Ok(_) => Path::new("src/Lato-Light.ttf"),
-        Err(_) => panic!("No font file found")
+    .build() {
+    Ok(w) => w,
+    Err(e) => {
+      println!("Error: can't open a window: {}", e);
+      process::exit(1);
+    }
+  }
+}
+
+fn load_font(path: &Path) -> GlyphCache<'static> {
+  match GlyphCache::new(path) {
+    Ok(c) => c,
+    Err(e) => {
+      println!("Error: can't load font {}: {:?}", path.display(), e);
+      process::exit(1);
+    }
+  }
+}
+
+// one board, no server. runs the same App as a networked game, it just never sends
+// anything anywhere
+fn play_offline(config: &Config, font_path: &Path) {
+  let layout = Layout::new(1, config.block_size);
+  let mut window = open_window(&layout);
+  let mut app = App::new(layout, load_font(font_path), 1, rand::random(), 1, false);
+  app.started = false;
+  app.names = vec![config.name.clone().unwrap_or_default()];
+
+  let mut events = window.events().ups(TICKS_PER_SECOND);
+  while let Some(e) = events.next(&mut window) {
+    if let Some(r) = e.render_args() {
+      app.render(&r);
+    }
+
+    let key = match e {
+      Event::Input(Input::Press(Button::Keyboard(key))) => Some(key),
+      _ => None
+    };
+    let over = app.game.boards[0].state == board::GameState::Over;
+    if key == Some(Key::Return) && (!app.started || over) {
+      if app.started {
+        save_replay(config, &app);
       }
+      app.restart(rand::random());
+      continue
+    }
+    if !app.started {
+      continue
+    }
+
+    // nobody else to wait on, so the board keeps right up with the clock
+    if e.update_args().is_some() {
+      app.step();
     }
+    if key == Some(Key::P) && !over {
+      app.toggle_pause();
+    } else if let Some(action) = key.and_then(action_for_key) {
+      app.press(action);
+    }
+  }
+  if app.started {
+    save_replay(config, &app);
+  }
+}
+
+// write the round out to --record, if we're keeping it. each one gets its own file,
+// FILE.1, FILE.2 and so on. only lockstep games can be replayed; an authoritative
+// server never tells us who pressed what
+fn save_replay(config: &Config, app: &App) {
+  let path = match config.record {
+    Some(ref path) => format!("{}.{}", path, app.round),
+    None => return
   };
+  if app.authoritative {
+    println!("Error: can't record a game the server ran, not saving {}", path);
+    return;
+  }
+  let result = File::create(&path).and_then(|mut f| write!(f, "{}", app.replay()));
+  match result {
+    Ok(()) => println!("Saved the game to {}", path),
+    Err(e) => println!("Error: can't save the game to {}: {}", path, e)
+  }
+}
+
+fn load_replay(path: &str) -> Result<Replay> {
+  let mut text = String::new();
+  File::open(path).and_then(|mut f| f.read_to_string(&mut text))
+    .map_err(|e| format!("can't read {}: {}", path, e))?;
+  Replay::parse(&text).map_err(|e| format!("{}: {}", path, e))
+}
 
-  // Create a new game and run it.
-  let mut boards: Vec<board::Board> = Vec::new();
-  for _ in 0..NUM_BOARDS {
-    boards.push(Board::init_board());
+// ticks as minutes:seconds
+fn clock(ticks: u64) -> String {
+  let seconds = ticks / TICKS_PER_SECOND;
+  format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+// play a replay through as fast as it goes and make sure every board ends up where
+// it did when it was recorded
+fn check_replay(replay: &Replay) -> Result<()> {
+  let game = replay::run(replay)?;
+  for (i, board) in game.boards.iter().enumerate() {
+    let name = replay.names[i].clone();
+    println!("Player {} {}: {} points, {} lines", i + 1, name, board.score, board.lines_cleared);
   }
+  replay.check(&game)
+}
 
-  // Create a new game and run it.
-  let mut app = App {
-    gl: GlGraphics::new(opengl),
-    boards: boards,
-    cache: GlyphCache::new(font_path).unwrap(),
-    token: -1,
-    winner: -1
+// watch a replay in the window. space pauses, left and right seek, up and down
+// change the speed, and home starts it over
+fn watch_replay(config: &Config, font_path: &Path, replay: Replay) {
+  let layout = Layout::new(replay.num_players, config.block_size);
+  let mut window = open_window(&layout);
+  let mut app = App::new(layout, load_font(font_path), replay.num_players, replay.seed, 0, false);
+  app.names = replay.names.clone();
+  app.watching = true;
+  let mut playback = Playback::new(replay);
+  app.game = match playback.start() {
+    Ok(game) => game,
+    Err(e) => {
+      println!("Error: bad replay: {}", e);
+      return;
+    }
   };
 
+  let mut events = window.events().ups(TICKS_PER_SECOND);
+  while let Some(e) = events.next(&mut window) {
+    if let Some(r) = e.render_args() {
+      app.render(&r);
+      app.render_playback(&r, &playback);
+    }
+
+    let result = if let Some(u) = e.update_args() {
+      playback.update(&mut app.game, u.dt)
+    } else if let Event::Input(Input::Press(Button::Keyboard(key))) = e {
+      let seek = SEEK_SECONDS * TICKS_PER_SECOND;
+      let tick = playback.tick();
+      match key {
+        Key::Space => {
+          playback.paused = !playback.paused;
+          Ok(())
+        },
+        Key::Left => playback.seek(&mut app.game, tick.saturating_sub(seek)),
+        Key::Right => playback.seek(&mut app.game, tick + seek),
+        Key::Up => {
+          let speed = playback.speed * 2.0;
+          playback.set_speed(speed);
+          Ok(())
+        },
+        Key::Down => {
+          let speed = playback.speed / 2.0;
+          playback.set_speed(speed);
+          Ok(())
+        },
+        Key::Home => playback.seek(&mut app.game, 0),
+        _ => Ok(())
+      }
+    } else {
+      Ok(())
+    };
+    if let Err(e) = result {
+      println!("Error: bad replay: {}", e);
+      return;
+    }
+  }
+}
+
+fn frame_message(frame: Frame) -> Message<'static> {
+  match frame {
+    Frame::Text(text) => Message::text(text),
+    Frame::Binary(bytes) => Message::binary(bytes)
+  }
+}
+
+// what the game loop and receive loops tell the connection thread
+enum Outgoing {
+  Send(Message<'static>),
+  Joined(i32, u8), // we're this player, talking this version. said again if we reconnect
+  Lost(usize), // connection n stopped hearing from the server
+  Closed(usize) // the server hung up on connection n
+}
+
+// keeps us talking to the server. everything we send goes in through `outgoing`, and
+// everything the server sends comes out of `incoming`. if the connection drops once
+// we're in a game, we keep trying to get it back, rejoin as the same player and
+// carry on where we were
+fn run_connection(url: Url, tx_out: mpsc::Sender<Outgoing>, outgoing: mpsc::Receiver<Outgoing>,
+                  incoming: mpsc::Sender<Frame>) {
+  let mut joined: Option<(i32, u8)> = None;
+  let mut unsent: Option<Message<'static>> = None; // what we were sending when it dropped
+  let mut conn = 0;
+  let mut attempts = 0;
+  let mut backoff = MIN_BACKOFF_MS;
   loop {
-    match rx1.recv() {
-      Ok(msg) => {
-        let message = String::from_utf8(msg.payload.into_owned()).unwrap();
-        let mut split_msg = message.split_whitespace();
-        let command = split_msg.next().unwrap();
-        match command {
-          "NUM_CONNS" => {
-            if token == 0 {
-              let _ = split_msg.next().unwrap();
-              let token_val = split_msg.next().unwrap();
-              token = token_val.parse::<i32>().unwrap();
-              app.token = token;
-              println!("Joined game as Player {}!\n", app.token);
-              tx_2.send(Message::text(format!("CLIENT_ACK {}", token_val))).unwrap();
+    let client = loop {
+      let result = Client::connect(url.clone()).and_then(|request| request.send()).and_then(|response| {
+        response.validate()?;
+        Ok(response)
+      });
+      match result {
+        Ok(response) => break response.begin(),
+        Err(e) => {
+          // only worth retrying if there's a game to get back to
+          if joined.is_none() {
+            println!("Error: couldn't connect to {}: {}. Is the server running?", url, e);
+            return;
+          }
+          println!("Error: Connection: {}", e);
+          attempts += 1;
+          if attempts >= RECONNECT_ATTEMPTS {
+            println!("Error: Connection: giving up on {}", url);
+            return;
+          }
+          thread::sleep(Duration::from_millis(backoff));
+          backoff = cmp::min(backoff * 2, MAX_BACKOFF_MS);
+        }
+      }
+    };
+    conn += 1;
+    println!("Successfully connected");
+
+    let (mut sender, mut receiver) = client.split();
+    let id = conn;
+    let tx_conn = tx_out.clone();
+    let tx_frames = incoming.clone();
+    thread::spawn(move || {
+      // Receive loop
+      for message in receiver.incoming_messages() {
+        let message: Message = match message {
+          Ok(m) => m,
+          Err(e) => {
+            println!("Error: Receive Loop: {:?}", e);
+            break;
+          }
+        };
+        match message.opcode {
+          Type::Close => {
+            let _ = tx_conn.send(Outgoing::Closed(id));
+            return;
+          }
+          Type::Ping => {
+            let _ = tx_conn.send(Outgoing::Send(Message::pong(message.payload)));
+          },
+          // Pass along to games the message we received
+          Type::Binary => {
+            if tx_frames.send(Frame::Binary(message.payload.into_owned())).is_err() {
+              return;
             }
           },
-          "START!" => {
-            println!("START!\n");
-            tx_2.send(Message::text(format!("FIRST_BLOCK {} {:?}", app.token, app.boards[(app.token - 1) as usize].current_piece.name))).unwrap();
-            break;
+          _ => {
+            match String::from_utf8(message.payload.into_owned()) {
+              Ok(text) => {
+                if tx_frames.send(Frame::Text(text)).is_err() {
+                  return;
+                }
+              },
+              Err(_) => println!("Error: Receive Loop: message was not utf-8")
+            }
+          }
+        }
+      }
+      let _ = tx_conn.send(Outgoing::Lost(id));
+    });
+
+    // the server only knows us by our token, so that goes first
+    let rejoining = joined.is_some();
+    let mut turned_away = false;
+    if let Some((token, version)) = joined {
+      println!("Rejoining as Player {}", token);
+      let rejoin = ClientMessage::Rejoin { token: token, version: version };
+      if sender.send_message(&frame_message(rejoin.encode(protocol::LEGACY_VERSION))).is_err() {
+        continue;
+      }
+    }
+
+    // Send loop
+    loop {
+      let message = match unsent.take() {
+        Some(m) => m,
+        None => match outgoing.recv() {
+          Ok(Outgoing::Send(m)) => m,
+          Ok(Outgoing::Joined(token, version)) => {
+            joined = Some((token, version));
+            continue;
           },
-          _ => { }
+          Ok(Outgoing::Lost(n)) => if n == conn { break } else { continue },
+          Ok(Outgoing::Closed(n)) => {
+            if n != conn {
+              continue;
+            }
+            // the server can't always take us back straight away, so that's worth
+            // another go. otherwise it's done with us and there's nothing to go back to
+            if rejoining {
+              turned_away = true;
+              break;
+            }
+            let _ = sender.send_message(&Message::close());
+            return;
+          },
+          Err(e) => {
+            println!("Error: Send Loop: {:?}", e);
+            return;
+          }
+        }
+      };
+      if let Type::Close = message.opcode {
+        // If it's a close message, just send it and then return.
+        let _ = sender.send_message(&message);
+        return;
+      }
+      if let Err(e) = sender.send_message(&message) {
+        println!("Error: Send Loop: {:?}", e);
+        unsent = Some(message);
+        break;
+      }
+    }
+    if joined.is_none() {
+      return;
+    }
+    if turned_away {
+      println!("Error: Connection: the server turned us away");
+      attempts += 1;
+      if attempts >= RECONNECT_ATTEMPTS {
+        println!("Error: Connection: giving up on {}", url);
+        return;
+      }
+      thread::sleep(Duration::from_millis(backoff));
+      backoff = cmp::min(backoff * 2, MAX_BACKOFF_MS);
+    } else {
+      // this one worked for a while, so the next one gets the full number of tries
+      attempts = 0;
+      backoff = MIN_BACKOFF_MS;
+    }
+    println!("Lost the server, reconnecting");
+  }
+}
+
+// the font from the command line, or Lato wherever we're being run from
+fn font_path(config: &Config) -> Result<PathBuf> {
+  if let Some(ref font) = config.font {
+    return match OpenOptions::new().read(true).open(font) {
+      Ok(_) => Ok(PathBuf::from(font)),
+      Err(e) => Err(format!("can't open font {}: {}", font, e))
+    };
+  }
+  for path in ["Lato-Light.ttf", "src/Lato-Light.ttf"].iter() {
+    if OpenOptions::new().read(true).open(path).is_ok() {
+      return Ok(PathBuf::from(path));
+    }
+  }
+  Err("no font file found, pass one with --font".to_string())
+}
+
+fn main() {
+  let config = match Config::from_env() {
+    Ok(c) => c,
+    Err(e) => {
+      println!("Error: {}\n\n{}", e, config::USAGE);
+      process::exit(1);
+    }
+  };
+  if config.help {
+    println!("{}", config::USAGE);
+    return;
+  }
+  let replay = match config.replay {
+    Some(ref path) => match load_replay(path) {
+      Ok(r) => Some(r),
+      Err(e) => {
+        println!("Error: {}", e);
+        process::exit(1);
+      }
+    },
+    None => None
+  };
+  if config.headless {
+    if let Some(ref replay) = replay {
+      if let Err(e) = check_replay(replay) {
+        println!("Error: replay doesn't match: {}", e);
+        process::exit(1);
+      }
+      println!("Replay checks out");
+    }
+    return;
+  }
+  let font_path = match font_path(&config) {
+    Ok(p) => p,
+    Err(e) => {
+      println!("Error: {}", e);
+      process::exit(1);
+    }
+  };
+  if let Some(replay) = replay {
+    watch_replay(&config, &font_path, replay);
+    return;
+  }
+  if config.offline {
+    play_offline(&config, &font_path);
+    return;
+  }
+  let server = if config.spectate {
+    format!("{}{}", config.server.trim_end_matches('/'), protocol::SPECTATE_PATH)
+  } else {
+    config.server.clone()
+  };
+  let url = match Url::parse(&server) {
+    Ok(u) => u,
+    Err(e) => {
+      println!("Error: bad server address {}: {}", server, e);
+      process::exit(1);
+    }
+  };
+  println!("Connecting to {}", url);
+
+  let (tx, rx) = channel();
+  let (tx1, rx1) = channel();
+  let tx_conn = tx.clone();
+  let connection = thread::spawn(move || run_connection(url, tx_conn, rx, tx1));
+
+  // wait for the server to tell us how many players there are. the rest of the
+  // lobby happens in the window. we talk the legacy text protocol until the server
+  // says which version to use
+  let (num_players, token) = loop {
+    match rx1.recv() {
+      Ok(frame) => {
+        match ServerMessage::decode(&frame) {
+          Ok(ServerMessage::NumConns { num_players, token }) => break (num_players, token),
+          Ok(ServerMessage::Spectating(num_players)) => break (num_players, protocol::SPECTATOR_TOKEN),
+          Ok(_) => { },
+          Err(e) => {
+            println!("Error: Main Loop: {}: {:?}", e, frame);
+          }
         }
       },
-      _ => { }
+      Err(_) => {
+        // the connection thread has said why
+        process::exit(1);
+      }
     }
   };
+  let spectating = token == protocol::SPECTATOR_TOKEN;
+  match config.name {
+    _ if spectating => println!("Spectating a game of {} players\n", num_players),
+    Some(ref name) => println!("Joined game as {} (Player {} of {})!\n", name, token, num_players),
+    None => println!("Joined game as Player {} of {}!\n", token, num_players)
+  }
+  let ack = ClientMessage::Ack { token: token, version: protocol::PROTOCOL_VERSION, name: config.name.clone() };
+  if tx.send(Outgoing::Send(frame_message(ack.encode(protocol::LEGACY_VERSION)))).is_err() {
+    return;
+  }
+  let mut version = protocol::LEGACY_VERSION;
+
+  let layout = Layout::new(num_players, config.block_size);
+  let mut window = open_window(&layout);
 
-  let mut events = window.events().ups(5);
+  // the boards get built for real once START! says what seed to use
+  let mut app = App::new(layout, load_font(&font_path), num_players, 0, token, false);
+  app.started = false;
+  app.lobby = Some(Lobby { players: Vec::new(), countdown: None, ready: false });
+  app.watching = spectating;
+
+  let mut events = window.events().ups(TICKS_PER_SECOND);
   while let Some(e) = events.next(&mut window) {
     // handle commands from the server
     loop {
       match rx1.try_recv() {
-        Ok(msg) => {
-          let message = String::from_utf8(msg.payload.into_owned()).unwrap();
-          match message.as_str() {
-            "" => {
-              continue;
-            },
-            _ => { }
-          };
-          let mut split_msg = message.split_whitespace();
-          let command = split_msg.next().unwrap();
-          match command {
-            "KEYSTROKE" => {
-              let client_num_str = split_msg.next().unwrap();
-              let client_num = client_num_str.parse::<i32>().unwrap();
-              if client_num != app.token {
-                let keystroke_str = split_msg.next().unwrap();
-                // update board for client number client_num
-                let input_keystroke: Input = match keystroke_str {
-                  "UP" => Input::Press(Button::Keyboard(Key::Up)),
-                  "DOWN" => Input::Press(Button::Keyboard(Key::Down)),
-                  "LEFT" => Input::Press(Button::Keyboard(Key::Left)),
-                  "RIGHT" => Input::Press(Button::Keyboard(Key::Right)),
-                  "SPACE" => Input::Press(Button::Keyboard(Key::Space)),
-                  _ => Input::Press(Button::Keyboard(Key::A))
-                };
-                app.boards[(client_num - 1) as usize].handle_key_press(&input_keystroke);
+        Ok(frame) => {
+          // a bad message only gets logged, it shouldn't take the whole game down
+          let result = match ServerMessage::decode(&frame) {
+            Ok(ServerMessage::Relay(relayed)) => app.handle_relay(relayed),
+            Ok(ServerMessage::State(token, snapshot)) => app.handle_state(token, &snapshot).map(|_| None),
+            Ok(ServerMessage::Left(token, tick)) => app.handle_left(token, tick).map(|_| None),
+            Ok(ServerMessage::Version(v)) => {
+              if v > protocol::PROTOCOL_VERSION {
+                println!("Error: Main Loop: server picked protocol version {}, we only speak up to {}",
+                         v, protocol::PROTOCOL_VERSION);
+                return;
               }
+              version = v;
+              Ok(None)
+            },
+            Ok(ServerMessage::Lobby(players)) => {
+              app.handle_lobby(players);
+              Ok(None)
             },
-            "FIRST_BLOCK" => {
-              let client_num_str = split_msg.next().unwrap();
-              let client_num = client_num_str.parse::<i32>().unwrap();
-              if client_num != app.token {
-                let new_block_str = split_msg.next().unwrap();
-                let new_block = match new_block_str {
-                  "I" => TETROMINOS[0],
-                  "O" => TETROMINOS[1],
-                  "T" => TETROMINOS[2],
-                  "S" => TETROMINOS[3],
-                  "Z" => TETROMINOS[4],
-                  "J" => TETROMINOS[5],
-                  "L" => TETROMINOS[6],
-                  _ => TETROMINOS[0]
-                };
-                app.boards[(client_num - 1) as usize].current_piece = new_block;
-                app.boards[(client_num - 1) as usize].update_ghost_piece();
+            Ok(ServerMessage::RoundOver { winner, match_over, wins }) => {
+              save_replay(&config, &app);
+              app.handle_round_over(winner, match_over, wins).map(|_| None)
+            },
+            Ok(ServerMessage::Countdown(seconds)) => {
+              if let Some(ref mut lobby) = app.lobby {
+                lobby.countdown = Some(seconds);
               }
+              Ok(None)
             },
-            "NEW_BLOCK" => {
-              let client_num_str = split_msg.next().unwrap();
-              let client_num = client_num_str.parse::<i32>().unwrap();
-              if client_num != app.token {
-                let new_block_str = split_msg.next().unwrap();
-                let new_block = match new_block_str {
-                  "I" => TETROMINOS[0],
-                  "O" => TETROMINOS[1],
-                  "T" => TETROMINOS[2],
-                  "S" => TETROMINOS[3],
-                  "Z" => TETROMINOS[4],
-                  "J" => TETROMINOS[5],
-                  "L" => TETROMINOS[6],
-                  _ => TETROMINOS[0]
-                };
-                app.boards[(client_num - 1) as usize].next_piece = new_block;
-                app.boards[(client_num - 1) as usize].update_ghost_piece();
+            Ok(ServerMessage::Start { seed, authoritative }) => {
+              println!("START!\n");
+              // every client builds its boards from the match seed so they all get the same pieces.
+              // later rounds of a match go again on the same boards
+              let seed = seed.unwrap_or_else(rand::random);
+              if app.started {
+                app.restart(seed);
+              } else {
+                app.start(seed, authoritative);
+              }
+              // spectators have no place to get back to if they drop
+              if !app.watching {
+                let _ = tx.send(Outgoing::Joined(app.token, version));
               }
+              Ok(None)
             },
-            "GAME_OVER" => {
-              let winner_num_str = split_msg.next().unwrap();
-              let winner_num = winner_num_str.parse::<i32>().unwrap();
-              println!("==== GAME OVER ====\n WINNER: PLAYER {}, SCORE: {}", winner_num,
-                       app.boards[(winner_num - 1) as usize].score);
-              // process::exit(0);
+            Ok(_) => Ok(None),
+            Err(e) => Err(e)
+          };
+          match result {
+            Ok(Some(reply)) => {
+              let _ = tx.send(Outgoing::Send(frame_message(reply.encode(version))));
             },
-            _ => {
-              //println!("Unknown command\n");
-            }
+            Ok(None) => (),
+            Err(e) => println!("Error: Main Loop: {}: {:?}", e, frame)
           }
         },
         _ => {
@@ -446,50 +1375,49 @@ fn main() {
       app.render(&r);
     }
 
-    if let Some(_) = e.update_args() {
-      if app.boards[(app.token - 1) as usize].new_block {
-        // send new block message
-        match tx.send(Message::text(format!("NEW_BLOCK {} {:?}", app.token, app.boards[(app.token - 1) as usize].next_piece.name))) { 
-          Ok(()) => (),
-          Err(e) => {
-            println!("Error: Main Loop: {:?}", e);
-            break;
-          }
+    // with an authoritative server, all we do is send inputs and draw what it sends back.
+    // between rounds there's nothing to run, and nothing we send would count. spectators
+    // can still be catching up to the end, though
+    if e.update_args().is_some() && app.started && !app.authoritative && (app.winner == -1 || app.watching) {
+      let mut sent = true;
+      for message in app.step() {
+        if let Err(e) = tx.send(Outgoing::Send(frame_message(message.encode(version)))) {
+          println!("Error: Main Loop: {:?}", e);
+          sent = false;
+          break;
         }
       }
-      // app.update(&u);
-      app.update();
+      if !sent {
+        break;
+      }
     }
 
-    if let Event::Input(i) = e {
-      app.boards[(app.token - 1) as usize].handle_key_press(&i);
-
-      let mut keystroke = "";
-      match i {
-        Input::Press(but) => {
-          match but {
-            Button::Keyboard(Key::Up) => {
-              keystroke = "UP";
-            }
-            Button::Keyboard(Key::Left) => {
-              keystroke = "LEFT";
-            }
-            Button::Keyboard(Key::Right) => {
-              keystroke = "RIGHT";
-            }
-            Button::Keyboard(Key::Down) => {
-              keystroke = "DOWN";
-            }
-            Button::Keyboard(Key::Space) => {
-              keystroke = "SPACE";
-            }
-            _ => {}
-          }
+    if let Event::Input(Input::Press(Button::Keyboard(key))) = e {
+      let message = if app.watching {
+        None
+      } else if !app.started || app.match_over {
+        // ENTER says we're ready, or that we aren't after all. after a match it's
+        // back to the lobby for a rematch
+        if key == Key::Return && app.match_over {
+          app.started = false;
+          app.match_over = false;
         }
-        _ => {}
-      }
-      if keystroke.len() > 0 {
-        match tx.send(Message::text(format!("KEYSTROKE {} {}", app.token, keystroke))) { 
+        match (key, app.lobby.as_mut()) {
+          (Key::Return, Some(lobby)) => {
+            lobby.ready = !lobby.ready;
+            Some(ClientMessage::Ready(app.token, lobby.ready))
+          },
+          _ => None
+        }
+      } else if app.winner != -1 {
+        None
+      } else if key == Key::P {
+        Some(app.toggle_pause())
+      } else {
+        action_for_key(key).map(|action| app.press(action))
+      };
+      if let Some(message) = message {
+        match tx.send(Outgoing::Send(frame_message(message.encode(version)))) {
           Ok(()) => (),
           Err(e) => {
             println!("Error: Main Loop: {:?}", e);
@@ -498,23 +1426,15 @@ fn main() {
         }
       }
     }
-    // check for game over; if game over then send GAME OVER msg
-    let mut num_ended_games = 0;
-    let mut winner = 0;
-    let mut i = 0;
-    for board in app.boards.iter() {
-      if board.state == board::GameState::Over {
-        num_ended_games += 1;
-      }
-      else {
-        winner = i + 1;
-      }
-      i += 1;
+    // check for game over; the game ends once at most one board is left standing.
+    // an authoritative server works this out itself
+    if !app.started || app.authoritative || app.watching || app.winner != -1 {
+      continue
     }
-    if num_ended_games == app.boards.len() - 1 && app.winner == -1 {
+    if let Some(winner) = board::winner(&app.game.boards) {
       // send game over message
-      app.winner = winner;
-      match tx.send(Message::text(format!("GAME_OVER {}", app.winner))) { 
+      app.winner = winner as i32 + 1;
+      match tx.send(Outgoing::Send(frame_message(ClientMessage::GameOver(app.winner).encode(version)))) {
         Ok(()) => (),
         Err(e) => {
           println!("Error: Main Loop: {:?}", e);
@@ -524,8 +1444,11 @@ fn main() {
     }
   }
 
-  // Exiting
-  let _ = send_loop.join();
-  let _ = receive_loop.join();
+  // Exiting. finished rounds have been saved already
+  if app.started && app.winner == -1 {
+    save_replay(&config, &app);
+  }
+  let _ = tx.send(Outgoing::Send(Message::close()));
+  let _ = connection.join();
 }
 
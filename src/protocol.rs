@@ -0,0 +1,1152 @@
+use std::fmt;
+use std::str;
+use std::str::{FromStr, SplitWhitespace};
+
+use super::BOARD_WIDTH;
+use super::BOARD_HEIGHT;
+use super::block::{Result, Shape, Tetromino};
+use super::board::{Action, Cell, Clear, GameState, Snapshot, Spin, MAX_LEVEL};
+
+// protocol versions. v0 is the original space separated text, v1 is a compact binary
+// encoding. clients say which version they speak in CLIENT_ACK, and the server answers
+// with a VERSION message if they speak something newer than v0
+pub const LEGACY_VERSION: u8 = 0;
+pub const PROTOCOL_VERSION: u8 = 1; // newest version we speak
+
+// one websocket message, before or after it's been decoded
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+  Text(String),
+  Binary(Vec<u8>)
+}
+
+// messages clients send to the server. everything but CLIENT_ACK and REJOIN gets
+// passed on to the other players as is
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientMessage {
+  Ack { token: i32, version: u8, name: Option<String> }, // CLIENT_ACK token version name
+  Input(i32, u64, Action), // KEYSTROKE token key tick, or HOLD token tick
+  // KEYSTROKE token key, or HOLD token, from v0 clients that don't know about ticks.
+  // the server picks a tick for it and passes it on as an Input
+  LegacyInput(i32, Action),
+  // FIRST_BLOCK token shape and NEW_BLOCK token shape, also only from v0 clients. each
+  // one picks its own pieces, so it tells the others its first one and every next one
+  FirstBlock(i32, Shape),
+  NewBlock(i32, Shape),
+  Confirm(i32, u64), // CONFIRM token tick, all of token's inputs before tick have been sent
+  Garbage { from: i32, to: i32, lines: u32, hole: usize }, // GARBAGE from to lines hole
+  GameOver(i32), // GAME_OVER winner
+  Checksum(i32, u64, u64), // CHECKSUM token tick checksum, of token's own board
+  Desync(i32, i32), // DESYNC token board, token's copy of board doesn't match
+  Resync(i32, u64, Box<Snapshot>), // RESYNC token tick snapshot, token's board as of tick
+  Rejoin { token: i32, version: u8 }, // REJOIN token version, first thing on a new connection
+  Ready(i32, bool), // READY token 1/0, token is (or isn't) ready to start
+  Pause(i32, u64, bool) // PAUSE token tick 1/0, every board pauses (or resumes) on tick
+}
+
+// messages the server sends to clients
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerMessage {
+  NumConns { num_players: usize, token: i32 }, // NUM_CONNS num_players token
+  Start { seed: Option<u64>, authoritative: bool }, // START! seed [AUTHORITATIVE]
+  State(i32, Box<Snapshot>), // STATE token snapshot, only from an authoritative server
+  Version(u8), // VERSION version, which encoding to use from now on
+  Left(i32, u64), // LEFT token tick, token dropped and didn't come back. gone from tick on
+  Lobby(Vec<LobbyPlayer>), // LOBBY n token name ready ..., everyone waiting for the game
+  Countdown(u8), // COUNTDOWN seconds until START!
+  Spectating(usize), // SPECTATING num_players, instead of NUM_CONNS for spectators
+  // ROUND_OVER winner match_over n wins..., who won the round and how many rounds
+  // each player has won so far. once the match is over it's back to the lobby
+  RoundOver { winner: i32, match_over: bool, wins: Vec<u32> },
+  Relay(ClientMessage) // another player's message
+}
+
+// spectators connect to this path instead of the root, and get a SPECTATING instead
+// of a place in the game. they ack with this token, and can't send anything else
+pub const SPECTATE_PATH: &str = "/spectate";
+pub const SPECTATOR_TOKEN: i32 = 0;
+
+// somebody in the lobby
+#[derive(Debug, Clone, PartialEq)]
+pub struct LobbyPlayer {
+  pub token: i32,
+  pub name: String,
+  pub ready: bool
+}
+
+pub const MAX_NAME_LENGTH: usize = 16;
+
+// names go over the wire between spaces, so they can't have any
+pub fn check_name(name: &str) -> Result<()> {
+  if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH ||
+     name.chars().any(|c| c.is_whitespace() || c.is_control()) {
+    return Err(format!("names are 1 to {} characters with no spaces, got {:?}", MAX_NAME_LENGTH, name));
+  }
+  Ok(())
+}
+
+// turns a player token into an index into the boards, if there is such a board
+pub fn board_index(token: i32, num_players: usize) -> Result<usize> {
+  if token >= 1 && token as usize <= num_players {
+    Ok((token - 1) as usize)
+  } else {
+    Err(format!("no player {} in a game of {}", token, num_players))
+  }
+}
+
+// next whitespace separated field of a message, parsed as a T
+fn field<T: FromStr>(fields: &mut SplitWhitespace, name: &str) -> Result<T> {
+  match fields.next() {
+    Some(s) => s.parse::<T>().map_err(|_| format!("bad {}: {}", name, s)),
+    None => Err(format!("missing {}", name))
+  }
+}
+
+fn keystroke_name(action: Action) -> &'static str {
+  match action {
+    Action::MoveLeft => "LEFT",
+    Action::MoveRight => "RIGHT",
+    Action::SoftDrop => "DOWN",
+    Action::HardDrop => "SPACE",
+    Action::RotateCw => "UP",
+    Action::RotateCcw => "CCW",
+    Action::Hold => "HOLD"
+  }
+}
+
+// v0 clients name pieces the way they're written in the code
+fn shape_for_name(name: &str) -> Result<Shape> {
+  match SHAPES.iter().find(|shape| format!("{:?}", shape) == name) {
+    Some(&shape) => Ok(shape),
+    None => Err(format!("unknown piece: {}", name))
+  }
+}
+
+fn action_for_keystroke(keystroke: &str) -> Result<Action> {
+  match keystroke {
+    "LEFT" => Ok(Action::MoveLeft),
+    "RIGHT" => Ok(Action::MoveRight),
+    "DOWN" => Ok(Action::SoftDrop),
+    "SPACE" => Ok(Action::HardDrop),
+    "UP" => Ok(Action::RotateCw),
+    "CCW" => Ok(Action::RotateCcw),
+    _ => Err(format!("unknown keystroke: {}", keystroke))
+  }
+}
+
+impl ClientMessage {
+  pub fn parse(text: &str) -> Result<ClientMessage> {
+    let mut fields = text.split_whitespace();
+    let command = match fields.next() {
+      Some(c) => c,
+      None => return Err("empty message".to_string())
+    };
+    match command {
+      "CLIENT_ACK" => {
+        let token = field(&mut fields, "token")?;
+        // clients from before versioning don't send one, or a name
+        let version = match fields.next() {
+          Some(v) => v.parse::<u8>().map_err(|_| format!("bad version: {}", v))?,
+          None => LEGACY_VERSION
+        };
+        let name = match fields.next() {
+          Some(name) => Some(fields_name(name)?),
+          None => None
+        };
+        if fields.next().is_some() {
+          return Err("extra fields".to_string());
+        }
+        Ok(ClientMessage::Ack { token: token, version: version, name: name })
+      },
+      "KEYSTROKE" => {
+        let token = field(&mut fields, "token")?;
+        let keystroke: String = field(&mut fields, "keystroke")?;
+        let action = action_for_keystroke(&keystroke)?;
+        // clients from before lockstep don't send a tick
+        match fields.next() {
+          Some(t) => Ok(ClientMessage::Input(token, t.parse::<u64>().map_err(|_| format!("bad tick: {}", t))?, action)),
+          None => Ok(ClientMessage::LegacyInput(token, action))
+        }
+      },
+      "HOLD" => {
+        let token = field(&mut fields, "token")?;
+        match fields.next() {
+          Some(t) => Ok(ClientMessage::Input(token, t.parse::<u64>().map_err(|_| format!("bad tick: {}", t))?, Action::Hold)),
+          None => Ok(ClientMessage::LegacyInput(token, Action::Hold))
+        }
+      },
+      "FIRST_BLOCK" | "NEW_BLOCK" => {
+        let token = field(&mut fields, "token")?;
+        let name: String = field(&mut fields, "piece")?;
+        let shape = shape_for_name(&name)?;
+        if command == "FIRST_BLOCK" {
+          Ok(ClientMessage::FirstBlock(token, shape))
+        } else {
+          Ok(ClientMessage::NewBlock(token, shape))
+        }
+      },
+      "CONFIRM" => {
+        let token = field(&mut fields, "token")?;
+        Ok(ClientMessage::Confirm(token, field(&mut fields, "tick")?))
+      },
+      "GARBAGE" => {
+        let from = field(&mut fields, "token")?;
+        let to = field(&mut fields, "target")?;
+        let lines = field(&mut fields, "lines")?;
+        let hole = field(&mut fields, "hole")?;
+        if lines as i64 > BOARD_HEIGHT {
+          return Err(format!("too much garbage: {}", lines));
+        }
+        if hole as i64 >= BOARD_WIDTH {
+          return Err(format!("hole off the board: {}", hole));
+        }
+        Ok(ClientMessage::Garbage { from: from, to: to, lines: lines, hole: hole })
+      },
+      "GAME_OVER" => Ok(ClientMessage::GameOver(field(&mut fields, "winner")?)),
+      "CHECKSUM" => {
+        let token = field(&mut fields, "token")?;
+        let tick = field(&mut fields, "tick")?;
+        Ok(ClientMessage::Checksum(token, tick, field(&mut fields, "checksum")?))
+      },
+      "DESYNC" => {
+        let token = field(&mut fields, "token")?;
+        Ok(ClientMessage::Desync(token, field(&mut fields, "board")?))
+      },
+      "RESYNC" => {
+        let token = field(&mut fields, "token")?;
+        let tick = field(&mut fields, "tick")?;
+        let snapshot = read_snapshot(&mut fields)?;
+        if fields.next().is_some() {
+          return Err("extra fields".to_string());
+        }
+        Ok(ClientMessage::Resync(token, tick, Box::new(snapshot)))
+      },
+      "REJOIN" => {
+        let token = field(&mut fields, "token")?;
+        Ok(ClientMessage::Rejoin { token: token, version: field(&mut fields, "version")? })
+      },
+      "READY" => {
+        let token = field(&mut fields, "token")?;
+        Ok(ClientMessage::Ready(token, read_bool(&mut fields)?))
+      },
+      "PAUSE" => {
+        let token = field(&mut fields, "token")?;
+        let tick = field(&mut fields, "tick")?;
+        Ok(ClientMessage::Pause(token, tick, read_bool(&mut fields)?))
+      },
+      _ => Err(format!("unknown command: {}", command))
+    }
+  }
+
+  // player who sent this message
+  pub fn token(&self) -> i32 {
+    match *self {
+      ClientMessage::Ack { token, .. } => token,
+      ClientMessage::Input(token, _, _) => token,
+      ClientMessage::LegacyInput(token, _) => token,
+      ClientMessage::FirstBlock(token, _) => token,
+      ClientMessage::NewBlock(token, _) => token,
+      ClientMessage::Confirm(token, _) => token,
+      ClientMessage::Garbage { from, .. } => from,
+      ClientMessage::Checksum(token, _, _) => token,
+      ClientMessage::Desync(token, _) => token,
+      ClientMessage::Resync(token, _, _) => token,
+      ClientMessage::Rejoin { token, .. } => token,
+      ClientMessage::Ready(token, _) => token,
+      ClientMessage::Pause(token, _, _) => token,
+      // anybody can call the winner
+      ClientMessage::GameOver(winner) => winner
+    }
+  }
+}
+
+impl fmt::Display for ClientMessage {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      ClientMessage::Ack { token, version: LEGACY_VERSION, name: None } => write!(f, "CLIENT_ACK {}", token),
+      ClientMessage::Ack { token, version, name: None } => write!(f, "CLIENT_ACK {} {}", token, version),
+      ClientMessage::Ack { token, version, name: Some(ref name) } =>
+        write!(f, "CLIENT_ACK {} {} {}", token, version, name),
+      // holding gets its own message
+      ClientMessage::Input(token, tick, Action::Hold) => write!(f, "HOLD {} {}", token, tick),
+      ClientMessage::Input(token, tick, action) =>
+        write!(f, "KEYSTROKE {} {} {}", token, keystroke_name(action), tick),
+      ClientMessage::LegacyInput(token, Action::Hold) => write!(f, "HOLD {}", token),
+      ClientMessage::LegacyInput(token, action) => write!(f, "KEYSTROKE {} {}", token, keystroke_name(action)),
+      ClientMessage::FirstBlock(token, shape) => write!(f, "FIRST_BLOCK {} {:?}", token, shape),
+      ClientMessage::NewBlock(token, shape) => write!(f, "NEW_BLOCK {} {:?}", token, shape),
+      ClientMessage::Confirm(token, tick) => write!(f, "CONFIRM {} {}", token, tick),
+      ClientMessage::Garbage { from, to, lines, hole } =>
+        write!(f, "GARBAGE {} {} {} {}", from, to, lines, hole),
+      ClientMessage::GameOver(winner) => write!(f, "GAME_OVER {}", winner),
+      ClientMessage::Checksum(token, tick, checksum) => write!(f, "CHECKSUM {} {} {}", token, tick, checksum),
+      ClientMessage::Desync(token, board) => write!(f, "DESYNC {} {}", token, board),
+      ClientMessage::Resync(token, tick, ref snapshot) => {
+        let mut text = format!("RESYNC {} {}", token, tick);
+        write_snapshot(&mut text, snapshot);
+        write!(f, "{}", text)
+      },
+      ClientMessage::Rejoin { token, version } => write!(f, "REJOIN {} {}", token, version),
+      ClientMessage::Ready(token, ready) => write!(f, "READY {} {}", token, ready as u8),
+      ClientMessage::Pause(token, tick, paused) => write!(f, "PAUSE {} {} {}", token, tick, paused as u8)
+    }
+  }
+}
+
+impl ServerMessage {
+  pub fn parse(text: &str) -> Result<ServerMessage> {
+    let mut fields = text.split_whitespace();
+    match fields.next() {
+      Some("NUM_CONNS") => {
+        let num_players = field(&mut fields, "number of players")?;
+        let token = field(&mut fields, "token")?;
+        board_index(token, num_players)?;
+        Ok(ServerMessage::NumConns { num_players: num_players, token: token })
+      },
+      Some("START!") => {
+        let seed = match fields.next() {
+          Some(s) => Some(s.parse::<u64>().map_err(|_| format!("bad seed: {}", s))?),
+          None => None
+        };
+        let authoritative = match fields.next() {
+          Some("AUTHORITATIVE") => true,
+          Some(s) => return Err(format!("bad game mode: {}", s)),
+          None => false
+        };
+        Ok(ServerMessage::Start { seed: seed, authoritative: authoritative })
+      },
+      Some("STATE") => {
+        let token = field(&mut fields, "token")?;
+        let snapshot = read_snapshot(&mut fields)?;
+        if fields.next().is_some() {
+          return Err("extra fields".to_string());
+        }
+        Ok(ServerMessage::State(token, Box::new(snapshot)))
+      },
+      Some("VERSION") => Ok(ServerMessage::Version(field(&mut fields, "version")?)),
+      Some("LEFT") => {
+        let token = field(&mut fields, "token")?;
+        Ok(ServerMessage::Left(token, field(&mut fields, "tick")?))
+      },
+      Some("LOBBY") => {
+        let players = read_lobby(&mut fields)?;
+        if fields.next().is_some() {
+          return Err("extra fields".to_string());
+        }
+        Ok(ServerMessage::Lobby(players))
+      },
+      Some("COUNTDOWN") => Ok(ServerMessage::Countdown(field(&mut fields, "seconds")?)),
+      Some("ROUND_OVER") => {
+        let winner = field(&mut fields, "winner")?;
+        let match_over = read_bool(&mut fields)?;
+        let wins = read_wins(&mut fields)?;
+        board_index(winner, wins.len())?;
+        if fields.next().is_some() {
+          return Err("extra fields".to_string());
+        }
+        Ok(ServerMessage::RoundOver { winner: winner, match_over: match_over, wins: wins })
+      },
+      Some("SPECTATING") => {
+        let num_players = field(&mut fields, "number of players")?;
+        board_index(1, num_players)?;
+        Ok(ServerMessage::Spectating(num_players))
+      },
+      _ => ClientMessage::parse(text).map(ServerMessage::Relay)
+    }
+  }
+}
+
+impl fmt::Display for ServerMessage {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      ServerMessage::NumConns { num_players, token } => write!(f, "NUM_CONNS {} {}", num_players, token),
+      ServerMessage::Start { seed: Some(seed), authoritative: true } => write!(f, "START! {} AUTHORITATIVE", seed),
+      ServerMessage::Start { seed: Some(seed), .. } => write!(f, "START! {}", seed),
+      ServerMessage::Start { seed: None, .. } => write!(f, "START!"),
+      ServerMessage::State(token, ref snapshot) => {
+        let mut text = format!("STATE {}", token);
+        write_snapshot(&mut text, snapshot);
+        write!(f, "{}", text)
+      },
+      ServerMessage::Version(version) => write!(f, "VERSION {}", version),
+      ServerMessage::Left(token, tick) => write!(f, "LEFT {} {}", token, tick),
+      ServerMessage::Lobby(ref players) => {
+        let mut text = "LOBBY".to_string();
+        write_lobby(&mut text, players);
+        write!(f, "{}", text)
+      },
+      ServerMessage::Countdown(seconds) => write!(f, "COUNTDOWN {}", seconds),
+      ServerMessage::Spectating(num_players) => write!(f, "SPECTATING {}", num_players),
+      ServerMessage::RoundOver { winner, match_over, ref wins } => {
+        let mut text = format!("ROUND_OVER {} {}", winner, match_over as u8);
+        write_wins(&mut text, wins);
+        write!(f, "{}", text)
+      },
+      ServerMessage::Relay(ref message) => write!(f, "{}", message)
+    }
+  }
+}
+
+// binary encoding (v1). every message is the version byte, a tag byte saying which
+// message it is, then the fields in order. numbers are big endian
+const TAG_ACK: u8 = 1;
+const TAG_INPUT: u8 = 2;
+const TAG_GARBAGE: u8 = 3;
+const TAG_GAME_OVER: u8 = 4;
+const TAG_CONFIRM: u8 = 5;
+const TAG_CHECKSUM: u8 = 6;
+const TAG_DESYNC: u8 = 7;
+const TAG_RESYNC: u8 = 8;
+const TAG_REJOIN: u8 = 9;
+const TAG_READY: u8 = 10;
+const TAG_PAUSE: u8 = 11;
+const TAG_NUM_CONNS: u8 = 16;
+const TAG_START: u8 = 17;
+const TAG_VERSION: u8 = 18;
+const TAG_STATE: u8 = 19;
+const TAG_LEFT: u8 = 20;
+const TAG_LOBBY: u8 = 21;
+const TAG_COUNTDOWN: u8 = 22;
+const TAG_SPECTATING: u8 = 23;
+const TAG_ROUND_OVER: u8 = 24;
+
+const ACTIONS: [Action; 7] = [Action::MoveLeft, Action::MoveRight, Action::SoftDrop, Action::HardDrop,
+                              Action::RotateCw, Action::RotateCcw, Action::Hold];
+
+const SHAPES: [Shape; 7] = [Shape::I, Shape::O, Shape::T, Shape::S, Shape::Z, Shape::J, Shape::L];
+
+// somewhere to write numbers to. text gets them space separated, binary gets them
+// big endian, so a message with lots of fields (STATE) only has to be written once
+trait Sink {
+  fn put_u8(&mut self, n: u8);
+  fn put_u32(&mut self, n: u32);
+  fn put_i32(&mut self, n: i32);
+  fn put_u64(&mut self, n: u64);
+  fn put_name(&mut self, name: &str);
+}
+
+impl Sink for Vec<u8> {
+  fn put_u8(&mut self, n: u8) {
+    self.push(n);
+  }
+
+  fn put_u32(&mut self, n: u32) {
+    self.put_i32(n as i32);
+  }
+
+  fn put_i32(&mut self, n: i32) {
+    for shift in [24, 16, 8, 0].iter() {
+      self.push((n >> shift) as u8);
+    }
+  }
+
+  fn put_u64(&mut self, n: u64) {
+    for i in 0..8 {
+      self.push((n >> (56 - 8 * i)) as u8);
+    }
+  }
+
+  // length first. names are short enough for that to fit in a byte
+  fn put_name(&mut self, name: &str) {
+    self.push(name.len() as u8);
+    self.extend_from_slice(name.as_bytes());
+  }
+}
+
+impl Sink for String {
+  fn put_u8(&mut self, n: u8) {
+    self.push_str(&format!(" {}", n));
+  }
+
+  fn put_u32(&mut self, n: u32) {
+    self.push_str(&format!(" {}", n));
+  }
+
+  fn put_i32(&mut self, n: i32) {
+    self.push_str(&format!(" {}", n));
+  }
+
+  fn put_u64(&mut self, n: u64) {
+    self.push_str(&format!(" {}", n));
+  }
+
+  fn put_name(&mut self, name: &str) {
+    self.push_str(&format!(" {}", name));
+  }
+}
+
+// and the other way around
+trait Source {
+  fn u8(&mut self) -> Result<u8>;
+  fn u32(&mut self) -> Result<u32>;
+  fn i32(&mut self) -> Result<i32>;
+  fn u64(&mut self) -> Result<u64>;
+  fn name(&mut self) -> Result<String>;
+}
+
+impl<'a> Source for SplitWhitespace<'a> {
+  fn u8(&mut self) -> Result<u8> {
+    field(self, "number")
+  }
+
+  fn u32(&mut self) -> Result<u32> {
+    field(self, "number")
+  }
+
+  fn i32(&mut self) -> Result<i32> {
+    field(self, "number")
+  }
+
+  fn u64(&mut self) -> Result<u64> {
+    field(self, "number")
+  }
+
+  fn name(&mut self) -> Result<String> {
+    match self.next() {
+      Some(name) => fields_name(name),
+      None => Err("missing name".to_string())
+    }
+  }
+}
+
+fn fields_name(name: &str) -> Result<String> {
+  check_name(name)?;
+  Ok(name.to_string())
+}
+
+fn read_bool<S: Source>(input: &mut S) -> Result<bool> {
+  match input.u8()? {
+    0 => Ok(false),
+    1 => Ok(true),
+    n => Err(format!("bad flag: {}", n))
+  }
+}
+
+fn write_lobby<S: Sink>(out: &mut S, players: &[LobbyPlayer]) {
+  out.put_u8(players.len() as u8);
+  for player in players.iter() {
+    out.put_i32(player.token);
+    out.put_name(&player.name);
+    out.put_u8(player.ready as u8);
+  }
+}
+
+fn read_lobby<S: Source>(input: &mut S) -> Result<Vec<LobbyPlayer>> {
+  let count = input.u8()?;
+  let mut players = Vec::new();
+  for _ in 0..count {
+    let token = input.i32()?;
+    let name = input.name()?;
+    players.push(LobbyPlayer { token: token, name: name, ready: read_bool(input)? });
+  }
+  Ok(players)
+}
+
+fn write_wins<S: Sink>(out: &mut S, wins: &[u32]) {
+  out.put_u8(wins.len() as u8);
+  for &n in wins.iter() {
+    out.put_u32(n);
+  }
+}
+
+fn read_wins<S: Source>(input: &mut S) -> Result<Vec<u32>> {
+  let count = input.u8()?;
+  let mut wins = Vec::new();
+  for _ in 0..count {
+    wins.push(input.u32()?);
+  }
+  Ok(wins)
+}
+
+fn shape_index(shape: Shape) -> u8 {
+  SHAPES.iter().position(|&s| s == shape).unwrap() as u8
+}
+
+fn put_shape<S: Sink>(out: &mut S, shape: Shape) {
+  out.put_u8(shape_index(shape));
+}
+
+fn read_shape<S: Source>(input: &mut S) -> Result<Shape> {
+  let n = input.u8()?;
+  match SHAPES.get(n as usize) {
+    Some(&shape) => Ok(shape),
+    None => Err(format!("unknown shape: {}", n))
+  }
+}
+
+// f64s go over as their bits so they come out exactly the same
+fn put_f64<S: Sink>(out: &mut S, n: f64) {
+  out.put_u64(n.to_bits());
+}
+
+fn read_f64<S: Source>(input: &mut S) -> Result<f64> {
+  Ok(f64::from_bits(input.u64()?))
+}
+
+// NaN or infinity would stop the timer from ever running out
+fn read_timer<S: Source>(input: &mut S) -> Result<f64> {
+  let time = read_f64(input)?;
+  if !time.is_finite() || time < 0.0 {
+    return Err(format!("bad timer: {}", time));
+  }
+  Ok(time)
+}
+
+// only pieces with every block on the board are any use to us
+fn read_piece<S: Source>(input: &mut S) -> Result<Tetromino> {
+  let mut piece = Tetromino::spawn(read_shape(input)?);
+  let rotation = input.u8()?;
+  if rotation > 3 {
+    return Err(format!("bad rotation: {}", rotation));
+  }
+  for _ in 0..rotation {
+    piece.rotate_right();
+  }
+  piece.x_offset = input.i32()? as f64;
+  piece.y_offset = input.i32()? as f64;
+  for row in 0..4 {
+    for col in 0..4 {
+      if piece.blocks[row][col] == 1 {
+        let x = col as f64 + piece.x_offset;
+        let y = row as f64 + piece.y_offset;
+        if x < 0.0 || x >= BOARD_WIDTH as f64 || y < 0.0 || y >= BOARD_HEIGHT as f64 {
+          return Err("piece off the board".to_string());
+        }
+      }
+    }
+  }
+  Ok(piece)
+}
+
+fn write_snapshot<S: Sink>(out: &mut S, snapshot: &Snapshot) {
+  for row in snapshot.cells.iter() {
+    for cell in row.iter() {
+      match *cell {
+        Cell::Empty => out.put_u8(0),
+        Cell::Garbage => out.put_u8(1),
+        // shapes start at 0, so shift them past empty and garbage
+        Cell::Block(shape) => out.put_u8(shape_index(shape) + 2)
+      }
+    }
+  }
+  let piece = snapshot.current_piece;
+  put_shape(out, piece.name);
+  out.put_u8(piece.rotation as u8);
+  out.put_i32(piece.x_offset as i32);
+  out.put_i32(piece.y_offset as i32);
+  put_shape(out, snapshot.next_piece);
+  match snapshot.hold_piece {
+    Some(shape) => {
+      out.put_u8(1);
+      put_shape(out, shape);
+    },
+    None => out.put_u8(0)
+  }
+  out.put_u8(snapshot.hold_used as u8);
+  out.put_u8(match snapshot.state {
+    GameState::Playing => 0,
+    GameState::Paused => 1,
+    GameState::Over => 2
+  });
+  out.put_u64(snapshot.score);
+  out.put_u32(snapshot.level);
+  out.put_u32(snapshot.lines_cleared);
+  out.put_u32(snapshot.combo);
+  out.put_u8(snapshot.back_to_back as u8);
+  match snapshot.last_clear {
+    Some(clear) => {
+      out.put_u8(1);
+      out.put_u8(clear.lines as u8);
+      out.put_u8(match clear.spin {
+        Spin::None => 0,
+        Spin::Mini => 1,
+        Spin::Full => 2
+      });
+      out.put_u8(clear.all_clear as u8);
+      out.put_u8(clear.back_to_back as u8);
+      out.put_u32(clear.combo);
+      out.put_u64(clear.points);
+    },
+    None => out.put_u8(0)
+  }
+  match snapshot.last_kick {
+    Some(kick) => out.put_u8(kick as u8 + 1),
+    None => out.put_u8(0)
+  }
+  put_f64(out, snapshot.gravity_timer);
+  put_f64(out, snapshot.lock_timer);
+  out.put_u32(snapshot.move_resets);
+  put_f64(out, snapshot.lowest_y);
+  out.put_u8(snapshot.pending_garbage.len() as u8);
+  for &(lines, hole) in snapshot.pending_garbage.iter() {
+    out.put_u32(lines);
+    out.put_u8(hole as u8);
+  }
+  out.put_u64(snapshot.seed);
+  out.put_u32(snapshot.bags_drawn);
+  out.put_u8(snapshot.bag.len() as u8);
+  for &shape in snapshot.bag.iter() {
+    put_shape(out, shape);
+  }
+}
+
+// limits on what a snapshot can say. a real game gets nowhere near them, but they
+// come from peers and restoring one has to be safe whatever's in it
+const MAX_SCORE: u64 = 1 << 48;
+const MAX_LINES_CLEARED: u32 = 1 << 24;
+const MAX_BAGS_DRAWN: u32 = 1 << 20; // restoring reshuffles every one of these
+
+fn read_snapshot<S: Source>(input: &mut S) -> Result<Snapshot> {
+  let mut cells = [[Cell::Empty; BOARD_WIDTH as usize]; BOARD_HEIGHT as usize];
+  for row in cells.iter_mut() {
+    for cell in row.iter_mut() {
+      *cell = match input.u8()? {
+        0 => Cell::Empty,
+        1 => Cell::Garbage,
+        n => match SHAPES.get(n as usize - 2) {
+          Some(&shape) => Cell::Block(shape),
+          None => return Err(format!("unknown cell: {}", n))
+        }
+      };
+    }
+  }
+  let current_piece = read_piece(input)?;
+  let next_piece = read_shape(input)?;
+  let hold_piece = match input.u8()? {
+    0 => None,
+    _ => Some(read_shape(input)?)
+  };
+  let hold_used = input.u8()? != 0;
+  let state = match input.u8()? {
+    0 => GameState::Playing,
+    1 => GameState::Paused,
+    2 => GameState::Over,
+    n => return Err(format!("unknown game state: {}", n))
+  };
+  let score = input.u64()?;
+  if score > MAX_SCORE {
+    return Err(format!("score too high: {}", score));
+  }
+  let level = input.u32()?;
+  if level == 0 || level > MAX_LEVEL {
+    return Err(format!("bad level: {}", level));
+  }
+  let lines_cleared = input.u32()?;
+  if lines_cleared > MAX_LINES_CLEARED {
+    return Err(format!("too many lines cleared: {}", lines_cleared));
+  }
+  // every lock that keeps a combo going clears at least one line
+  let combo = input.u32()?;
+  if combo > lines_cleared {
+    return Err(format!("combo of {} with {} lines cleared", combo, lines_cleared));
+  }
+  let back_to_back = input.u8()? != 0;
+  let last_clear = match input.u8()? {
+    0 => None,
+    _ => {
+      let lines = input.u8()? as usize;
+      if lines > 4 {
+        return Err(format!("can't clear {} lines", lines));
+      }
+      let spin = match input.u8()? {
+        0 => Spin::None,
+        1 => Spin::Mini,
+        2 => Spin::Full,
+        n => return Err(format!("unknown spin: {}", n))
+      };
+      let all_clear = input.u8()? != 0;
+      let back_to_back = input.u8()? != 0;
+      let combo = input.u32()?;
+      Some(Clear {
+        lines: lines,
+        spin: spin,
+        all_clear: all_clear,
+        back_to_back: back_to_back,
+        combo: combo,
+        points: input.u64()?
+      })
+    }
+  };
+  let last_kick = match input.u8()? {
+    0 => None,
+    n => Some(n as usize - 1)
+  };
+  let gravity_timer = read_timer(input)?;
+  let lock_timer = read_timer(input)?;
+  let move_resets = input.u32()?;
+  let lowest_y = read_f64(input)?;
+  if !lowest_y.is_finite() {
+    return Err(format!("bad lowest row: {}", lowest_y));
+  }
+  let mut pending_garbage = Vec::new();
+  for _ in 0..input.u8()? {
+    let lines = input.u32()?;
+    if lines as i64 > BOARD_HEIGHT {
+      return Err(format!("too much garbage: {}", lines));
+    }
+    let hole = input.u8()? as usize;
+    if hole as i64 >= BOARD_WIDTH {
+      return Err(format!("hole off the board: {}", hole));
+    }
+    pending_garbage.push((lines, hole));
+  }
+  let seed = input.u64()?;
+  let bags_drawn = input.u32()?;
+  if bags_drawn > MAX_BAGS_DRAWN {
+    return Err(format!("too many bags drawn: {}", bags_drawn));
+  }
+  let bag_len = input.u8()?;
+  if bag_len as usize > SHAPES.len() {
+    return Err(format!("bag too big: {}", bag_len));
+  }
+  let mut bag = Vec::new();
+  for _ in 0..bag_len {
+    bag.push(read_shape(input)?);
+  }
+  Ok(Snapshot {
+    cells: cells,
+    current_piece: current_piece,
+    next_piece: next_piece,
+    hold_piece: hold_piece,
+    hold_used: hold_used,
+    state: state,
+    score: score,
+    level: level,
+    lines_cleared: lines_cleared,
+    combo: combo,
+    back_to_back: back_to_back,
+    last_clear: last_clear,
+    last_kick: last_kick,
+    gravity_timer: gravity_timer,
+    lock_timer: lock_timer,
+    move_resets: move_resets,
+    lowest_y: lowest_y,
+    pending_garbage: pending_garbage,
+    seed: seed,
+    bags_drawn: bags_drawn,
+    bag: bag
+  })
+}
+
+// reads fields off the front of a binary message
+struct Reader<'a> {
+  bytes: &'a [u8],
+  pos: usize
+}
+
+impl<'a> Source for Reader<'a> {
+  fn u8(&mut self) -> Result<u8> {
+    match self.bytes.get(self.pos) {
+      Some(&b) => {
+        self.pos += 1;
+        Ok(b)
+      },
+      None => Err("message too short".to_string())
+    }
+  }
+
+  fn u32(&mut self) -> Result<u32> {
+    let mut n: u32 = 0;
+    for _ in 0..4 {
+      n = (n << 8) | self.u8()? as u32;
+    }
+    Ok(n)
+  }
+
+  fn i32(&mut self) -> Result<i32> {
+    Ok(self.u32()? as i32)
+  }
+
+  fn u64(&mut self) -> Result<u64> {
+    let mut n: u64 = 0;
+    for _ in 0..8 {
+      n = (n << 8) | self.u8()? as u64;
+    }
+    Ok(n)
+  }
+
+  fn name(&mut self) -> Result<String> {
+    let len = self.u8()? as usize;
+    if self.pos + len > self.bytes.len() {
+      return Err("message too short".to_string());
+    }
+    let bytes = &self.bytes[self.pos..self.pos + len];
+    self.pos += len;
+    match str::from_utf8(bytes) {
+      Ok(name) => fields_name(name),
+      Err(_) => Err("name is not utf-8".to_string())
+    }
+  }
+}
+
+impl<'a> Reader<'a> {
+  fn action(&mut self) -> Result<Action> {
+    let b = self.u8()?;
+    match ACTIONS.get(b as usize) {
+      Some(&action) => Ok(action),
+      None => Err(format!("unknown action: {}", b))
+    }
+  }
+
+  fn finish(&self) -> Result<()> {
+    if self.pos == self.bytes.len() {
+      Ok(())
+    } else {
+      Err(format!("{} extra bytes", self.bytes.len() - self.pos))
+    }
+  }
+}
+
+// checks the version byte and hands back the tag and a reader for the rest
+fn open_binary<'a>(bytes: &'a [u8]) -> Result<(u8, Reader<'a>)> {
+  let mut reader = Reader { bytes: bytes, pos: 0 };
+  let version = reader.u8()?;
+  if version == LEGACY_VERSION || version > PROTOCOL_VERSION {
+    return Err(format!("can't decode version {}", version));
+  }
+  let tag = reader.u8()?;
+  Ok((tag, reader))
+}
+
+impl ClientMessage {
+  // encode for a peer that speaks `version`
+  pub fn encode(&self, version: u8) -> Frame {
+    if version == LEGACY_VERSION {
+      return Frame::Text(self.to_string());
+    }
+    let mut bytes = vec![PROTOCOL_VERSION];
+    match *self {
+      // only v0 clients send these, and they only speak text
+      ClientMessage::LegacyInput(..) | ClientMessage::FirstBlock(..) | ClientMessage::NewBlock(..) =>
+        return Frame::Text(self.to_string()),
+      ClientMessage::Ack { token, version: ack_version, ref name } => {
+        // no name is an empty one
+        bytes.push(TAG_ACK);
+        bytes.put_i32(token);
+        bytes.push(ack_version);
+        bytes.put_name(name.as_ref().map(|n| n.as_str()).unwrap_or(""));
+      },
+      ClientMessage::Input(token, tick, action) => {
+        bytes.push(TAG_INPUT);
+        bytes.put_i32(token);
+        bytes.put_u64(tick);
+        bytes.push(ACTIONS.iter().position(|&a| a == action).unwrap() as u8);
+      },
+      ClientMessage::Confirm(token, tick) => {
+        bytes.push(TAG_CONFIRM);
+        bytes.put_i32(token);
+        bytes.put_u64(tick);
+      },
+      ClientMessage::Garbage { from, to, lines, hole } => {
+        // both fit in a byte, the parser won't take anything bigger than the board
+        bytes.push(TAG_GARBAGE);
+        bytes.put_i32(from);
+        bytes.put_i32(to);
+        bytes.push(lines as u8);
+        bytes.push(hole as u8);
+      },
+      ClientMessage::GameOver(winner) => {
+        bytes.push(TAG_GAME_OVER);
+        bytes.put_i32(winner);
+      },
+      ClientMessage::Checksum(token, tick, checksum) => {
+        bytes.push(TAG_CHECKSUM);
+        bytes.put_i32(token);
+        bytes.put_u64(tick);
+        bytes.put_u64(checksum);
+      },
+      ClientMessage::Desync(token, board) => {
+        bytes.push(TAG_DESYNC);
+        bytes.put_i32(token);
+        bytes.put_i32(board);
+      },
+      ClientMessage::Resync(token, tick, ref snapshot) => {
+        bytes.push(TAG_RESYNC);
+        bytes.put_i32(token);
+        bytes.put_u64(tick);
+        write_snapshot(&mut bytes, snapshot);
+      },
+      ClientMessage::Rejoin { token, version: rejoin_version } => {
+        bytes.push(TAG_REJOIN);
+        bytes.put_i32(token);
+        bytes.push(rejoin_version);
+      },
+      ClientMessage::Ready(token, ready) => {
+        bytes.push(TAG_READY);
+        bytes.put_i32(token);
+        bytes.push(ready as u8);
+      },
+      ClientMessage::Pause(token, tick, paused) => {
+        bytes.push(TAG_PAUSE);
+        bytes.put_i32(token);
+        bytes.put_u64(tick);
+        bytes.push(paused as u8);
+      }
+    }
+    Frame::Binary(bytes)
+  }
+
+  // decode a message in whatever version it was sent in
+  pub fn decode(frame: &Frame) -> Result<ClientMessage> {
+    match *frame {
+      Frame::Text(ref text) => ClientMessage::parse(text),
+      Frame::Binary(ref bytes) => {
+        let (tag, mut reader) = open_binary(bytes)?;
+        let message = ClientMessage::read(tag, &mut reader)?;
+        reader.finish()?;
+        Ok(message)
+      }
+    }
+  }
+
+  fn read(tag: u8, reader: &mut Reader) -> Result<ClientMessage> {
+    match tag {
+      TAG_ACK => {
+        let token = reader.i32()?;
+        let version = reader.u8()?;
+        let name = if reader.bytes.get(reader.pos) == Some(&0) {
+          reader.pos += 1;
+          None
+        } else {
+          Some(reader.name()?)
+        };
+        Ok(ClientMessage::Ack { token: token, version: version, name: name })
+      },
+      TAG_INPUT => Ok(ClientMessage::Input(reader.i32()?, reader.u64()?, reader.action()?)),
+      TAG_CONFIRM => Ok(ClientMessage::Confirm(reader.i32()?, reader.u64()?)),
+      TAG_GARBAGE => {
+        let from = reader.i32()?;
+        let to = reader.i32()?;
+        let lines = reader.u8()? as u32;
+        let hole = reader.u8()? as usize;
+        if lines as i64 > BOARD_HEIGHT {
+          return Err(format!("too much garbage: {}", lines));
+        }
+        if hole as i64 >= BOARD_WIDTH {
+          return Err(format!("hole off the board: {}", hole));
+        }
+        Ok(ClientMessage::Garbage { from: from, to: to, lines: lines, hole: hole })
+      },
+      TAG_GAME_OVER => Ok(ClientMessage::GameOver(reader.i32()?)),
+      TAG_CHECKSUM => Ok(ClientMessage::Checksum(reader.i32()?, reader.u64()?, reader.u64()?)),
+      TAG_DESYNC => Ok(ClientMessage::Desync(reader.i32()?, reader.i32()?)),
+      TAG_RESYNC => {
+        let token = reader.i32()?;
+        let tick = reader.u64()?;
+        Ok(ClientMessage::Resync(token, tick, Box::new(read_snapshot(reader)?)))
+      },
+      TAG_REJOIN => Ok(ClientMessage::Rejoin { token: reader.i32()?, version: reader.u8()? }),
+      TAG_READY => Ok(ClientMessage::Ready(reader.i32()?, read_bool(reader)?)),
+      TAG_PAUSE => Ok(ClientMessage::Pause(reader.i32()?, reader.u64()?, read_bool(reader)?)),
+      _ => Err(format!("unknown tag: {}", tag))
+    }
+  }
+}
+
+impl ServerMessage {
+  // encode for a peer that speaks `version`
+  pub fn encode(&self, version: u8) -> Frame {
+    if version == LEGACY_VERSION {
+      return Frame::Text(self.to_string());
+    }
+    let mut bytes = vec![PROTOCOL_VERSION];
+    match *self {
+      ServerMessage::NumConns { num_players, token } => {
+        bytes.push(TAG_NUM_CONNS);
+        bytes.push(num_players as u8);
+        bytes.put_i32(token);
+      },
+      ServerMessage::Start { seed, authoritative } => {
+        bytes.push(TAG_START);
+        bytes.push(authoritative as u8);
+        if let Some(seed) = seed {
+          bytes.put_u64(seed);
+        }
+      },
+      ServerMessage::State(token, ref snapshot) => {
+        bytes.push(TAG_STATE);
+        bytes.put_i32(token);
+        write_snapshot(&mut bytes, snapshot);
+      },
+      ServerMessage::Version(version) => {
+        bytes.push(TAG_VERSION);
+        bytes.push(version);
+      },
+      ServerMessage::Left(token, tick) => {
+        bytes.push(TAG_LEFT);
+        bytes.put_i32(token);
+        bytes.put_u64(tick);
+      },
+      ServerMessage::Lobby(ref players) => {
+        bytes.push(TAG_LOBBY);
+        write_lobby(&mut bytes, players);
+      },
+      ServerMessage::Countdown(seconds) => {
+        bytes.push(TAG_COUNTDOWN);
+        bytes.push(seconds);
+      },
+      ServerMessage::Spectating(num_players) => {
+        bytes.push(TAG_SPECTATING);
+        bytes.push(num_players as u8);
+      },
+      ServerMessage::RoundOver { winner, match_over, ref wins } => {
+        bytes.push(TAG_ROUND_OVER);
+        bytes.put_i32(winner);
+        bytes.push(match_over as u8);
+        write_wins(&mut bytes, wins);
+      },
+      ServerMessage::Relay(ref message) => return message.encode(version)
+    }
+    Frame::Binary(bytes)
+  }
+
+  // decode a message in whatever version it was sent in
+  pub fn decode(frame: &Frame) -> Result<ServerMessage> {
+    match *frame {
+      Frame::Text(ref text) => ServerMessage::parse(text),
+      Frame::Binary(ref bytes) => {
+        let (tag, mut reader) = open_binary(bytes)?;
+        let message = match tag {
+          TAG_NUM_CONNS => {
+            let num_players = reader.u8()? as usize;
+            let token = reader.i32()?;
+            board_index(token, num_players)?;
+            ServerMessage::NumConns { num_players: num_players, token: token }
+          },
+          TAG_START => {
+            let authoritative = reader.u8()? != 0;
+            let seed = if reader.pos == reader.bytes.len() {
+              None
+            } else {
+              Some(reader.u64()?)
+            };
+            ServerMessage::Start { seed: seed, authoritative: authoritative }
+          },
+          TAG_STATE => {
+            let token = reader.i32()?;
+            ServerMessage::State(token, Box::new(read_snapshot(&mut reader)?))
+          },
+          TAG_VERSION => ServerMessage::Version(reader.u8()?),
+          TAG_LEFT => ServerMessage::Left(reader.i32()?, reader.u64()?),
+          TAG_LOBBY => ServerMessage::Lobby(read_lobby(&mut reader)?),
+          TAG_COUNTDOWN => ServerMessage::Countdown(reader.u8()?),
+          TAG_ROUND_OVER => {
+            let winner = reader.i32()?;
+            let match_over = read_bool(&mut reader)?;
+            let wins = read_wins(&mut reader)?;
+            board_index(winner, wins.len())?;
+            ServerMessage::RoundOver { winner: winner, match_over: match_over, wins: wins }
+          },
+          TAG_SPECTATING => {
+            let num_players = reader.u8()? as usize;
+            board_index(1, num_players)?;
+            ServerMessage::Spectating(num_players)
+          },
+          _ => ServerMessage::Relay(ClientMessage::read(tag, &mut reader)?)
+        };
+        reader.finish()?;
+        Ok(message)
+      }
+    }
+  }
+}
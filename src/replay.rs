@@ -0,0 +1,261 @@
+// recorded games. since the boards are deterministic, a game is just its seed and
+// who pressed what on which tick, so that's all a replay keeps. playing one back runs
+// the same Lockstep sim the game ran on
+//
+// replays are saved as text, one thing per line, reusing the protocol's messages for
+// the events:
+//
+//   REPLAY version seed players
+//   NAME token name
+//   KEYSTROKE token key tick      (or HOLD token tick)
+//   LEFT token tick
+//   PAUSE token tick 1/0
+//   END ticks
+//   BOARD token score checksum    (how each board ended up)
+use std::cmp;
+use std::fmt;
+
+use super::{MAX_PLAYERS, TICKS_PER_SECOND};
+use super::block::Result;
+use super::board::Action;
+use super::lockstep;
+use super::lockstep::Lockstep;
+use super::protocol;
+use super::protocol::{ClientMessage, ServerMessage};
+
+pub const REPLAY_VERSION: u32 = 1;
+
+// playback speeds, as multiples of real time
+pub const MIN_SPEED: f64 = 0.25;
+pub const MAX_SPEED: f64 = 8.0;
+
+// something that happened to one of the boards
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+  Input(usize, Action), // player pressed action
+  Left(usize), // player dropped out of the game
+  Pause(usize, bool) // player paused (or resumed) every board
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Replay {
+  pub seed: u64,
+  pub num_players: usize,
+  pub names: Vec<String>, // empty if we don't know
+  pub events: Vec<(u64, Event)>, // by tick
+  pub length: u64, // ticks
+  pub results: Vec<(u64, u64)> // (score, checksum) of each board at the end, if known
+}
+
+impl Replay {
+  pub fn new(num_players: usize, seed: u64) -> Replay {
+    Replay {
+      seed: seed,
+      num_players: num_players,
+      names: vec![String::new(); num_players],
+      events: Vec::new(),
+      length: 0,
+      results: Vec::new()
+    }
+  }
+
+  pub fn parse(text: &str) -> Result<Replay> {
+    let mut lines = text.lines().map(|l| l.trim()).filter(|l| !l.is_empty());
+    let mut header = match lines.next() {
+      Some(line) => line.split_whitespace(),
+      None => return Err("empty replay".to_string())
+    };
+    if header.next() != Some("REPLAY") {
+      return Err("not a replay".to_string());
+    }
+    let version: u32 = number(header.next(), "version")?;
+    if version != REPLAY_VERSION {
+      return Err(format!("can't read replay version {}", version));
+    }
+    let seed = number(header.next(), "seed")?;
+    let num_players = number(header.next(), "number of players")?;
+    if !(1..=MAX_PLAYERS).contains(&num_players) {
+      return Err(format!("can't play back {} players", num_players));
+    }
+    let mut replay = Replay::new(num_players, seed);
+
+    let mut ended = false;
+    for line in lines {
+      let mut fields = line.split_whitespace();
+      match fields.next() {
+        Some("NAME") => {
+          let i = protocol::board_index(number(fields.next(), "token")?, num_players)?;
+          let name = fields.next().unwrap_or("");
+          protocol::check_name(name)?;
+          if fields.next().is_some() {
+            return Err(format!("extra fields: {}", line));
+          }
+          replay.names[i] = name.to_string();
+        },
+        Some("END") => {
+          replay.length = number(fields.next(), "length")?;
+          ended = true;
+        },
+        Some("BOARD") => {
+          let i = protocol::board_index(number(fields.next(), "token")?, num_players)?;
+          if i != replay.results.len() {
+            return Err(format!("board {} out of order", i + 1));
+          }
+          let score = number(fields.next(), "score")?;
+          replay.results.push((score, number(fields.next(), "checksum")?));
+        },
+        _ => {
+          let (tick, event) = match ServerMessage::parse(line)? {
+            ServerMessage::Relay(ClientMessage::Input(token, tick, action)) =>
+              (tick, Event::Input(protocol::board_index(token, num_players)?, action)),
+            ServerMessage::Left(token, tick) => (tick, Event::Left(protocol::board_index(token, num_players)?)),
+            ServerMessage::Relay(ClientMessage::Pause(token, tick, paused)) =>
+              (tick, Event::Pause(protocol::board_index(token, num_players)?, paused)),
+            _ => return Err(format!("not a replay event: {}", line))
+          };
+          // nobody can press anything before the first inputs could have arrived
+          match event {
+            Event::Input(..) | Event::Pause(..) if tick < lockstep::INPUT_DELAY =>
+              return Err(format!("input before tick {}: {}", lockstep::INPUT_DELAY, line)),
+            _ => ()
+          }
+          replay.events.push((tick, event));
+        }
+      }
+    }
+    if !ended {
+      return Err("replay has no END".to_string());
+    }
+    if !replay.results.is_empty() && replay.results.len() != num_players {
+      return Err(format!("results for {} of {} boards", replay.results.len(), num_players));
+    }
+    Ok(replay)
+  }
+
+  // does `game` look like it did when this was recorded? only the boards we have
+  // results for get checked
+  pub fn check(&self, game: &Lockstep) -> Result<()> {
+    for (i, &(score, checksum)) in self.results.iter().enumerate() {
+      let board = &game.boards[i];
+      if board.score != score || board.checksum() != checksum {
+        return Err(format!("board {} ended up with score {} (checksum {}), expected {} ({})",
+                           i + 1, board.score, board.checksum(), score, checksum));
+      }
+    }
+    Ok(())
+  }
+}
+
+impl fmt::Display for Replay {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(f, "REPLAY {} {} {}", REPLAY_VERSION, self.seed, self.num_players)?;
+    for (i, name) in self.names.iter().enumerate() {
+      if !name.is_empty() {
+        writeln!(f, "NAME {} {}", i + 1, name)?;
+      }
+    }
+    // events go in tick order, whatever order they were recorded in
+    let mut events = self.events.clone();
+    events.sort_by_key(|&(tick, _)| tick);
+    for &(tick, event) in events.iter() {
+      match event {
+        Event::Input(i, action) => writeln!(f, "{}", ClientMessage::Input(i as i32 + 1, tick, action))?,
+        Event::Left(i) => writeln!(f, "{}", ServerMessage::Left(i as i32 + 1, tick))?,
+        Event::Pause(i, paused) => writeln!(f, "{}", ClientMessage::Pause(i as i32 + 1, tick, paused))?
+      }
+    }
+    writeln!(f, "END {}", self.length)?;
+    for (i, &(score, checksum)) in self.results.iter().enumerate() {
+      writeln!(f, "BOARD {} {} {}", i + 1, score, checksum)?;
+    }
+    Ok(())
+  }
+}
+
+fn number<T: ::std::str::FromStr>(field: Option<&str>, name: &str) -> Result<T> {
+  match field {
+    Some(s) => s.parse::<T>().map_err(|_| format!("bad {}: {}", name, s)),
+    None => Err(format!("missing {}", name))
+  }
+}
+
+// plays a replay back a tick at a time, at whatever speed, on a game the caller owns
+// (so the window can draw it like any other)
+pub struct Playback {
+  pub replay: Replay,
+  pub speed: f64,
+  pub paused: bool,
+  clock: f64 // ticks played so far, with the fraction of the next one
+}
+
+impl Playback {
+  pub fn new(replay: Replay) -> Playback {
+    Playback {
+      replay: replay,
+      speed: 1.0,
+      paused: false,
+      clock: 0.0
+    }
+  }
+
+  // a fresh game with every event in the replay queued up
+  pub fn start(&self) -> Result<Lockstep> {
+    let mut game = Lockstep::new(self.replay.num_players, self.replay.seed);
+    for &(tick, event) in self.replay.events.iter() {
+      match event {
+        Event::Input(i, action) => game.add_input(i, tick, action)?,
+        Event::Left(i) => game.remove_player(i, tick),
+        Event::Pause(i, paused) => game.pause(i, tick, paused)?
+      }
+    }
+    Ok(game)
+  }
+
+  pub fn tick(&self) -> u64 {
+    self.clock as u64
+  }
+
+  pub fn finished(&self) -> bool {
+    self.tick() >= self.replay.length
+  }
+
+  // jump to `tick`. going backwards means starting over and playing up to it
+  pub fn seek(&mut self, game: &mut Lockstep, tick: u64) -> Result<()> {
+    let tick = cmp::min(tick, self.replay.length);
+    if tick < game.tick {
+      *game = self.start()?;
+    }
+    for i in 0..self.replay.num_players {
+      game.confirm(i, tick);
+    }
+    game.advance();
+    self.clock = tick as f64;
+    Ok(())
+  }
+
+  // `dt` seconds of real time have gone by
+  pub fn update(&mut self, game: &mut Lockstep, dt: f64) -> Result<()> {
+    if self.paused || self.finished() {
+      return Ok(())
+    }
+    let clock = self.clock + dt * self.speed * TICKS_PER_SECOND as f64;
+    self.seek(game, clock as u64)?;
+    if !self.finished() {
+      self.clock = clock;
+    }
+    Ok(())
+  }
+
+  pub fn set_speed(&mut self, speed: f64) {
+    self.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+  }
+}
+
+// plays the whole thing through without a window
+pub fn run(replay: &Replay) -> Result<Lockstep> {
+  let mut playback = Playback::new(replay.clone());
+  let mut game = playback.start()?;
+  let length = replay.length;
+  playback.seek(&mut game, length)?;
+  Ok(game)
+}
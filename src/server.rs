@@ -0,0 +1,990 @@
+extern crate rand;
+extern crate websocket;
+extern crate tetris;
+
+use std::cmp;
+use std::env;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::sync::mpsc;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use rand::Rng;
+use websocket::{Server, Message, Sender, Receiver};
+use websocket::message::Type;
+use tetris::{BOARD_WIDTH, MAX_PLAYERS, TICKS_PER_SECOND};
+use tetris::block::Result;
+use tetris::board;
+use tetris::board::{Board, GameState, Snapshot};
+use tetris::lockstep;
+use tetris::protocol;
+use tetris::protocol::{ClientMessage, Frame, LobbyPlayer, ServerMessage};
+
+// every interface, so players on other machines can get in. --listen to change it
+const DEFAULT_ADDR: &str = "0.0.0.0:3012";
+
+const DEFAULT_NUM_PLAYERS: usize = 2; // number of players in a game
+
+// how long a dropped player's slot is kept for them to come back to
+const REJOIN_GRACE_SECS: u64 = 30;
+
+// seconds between everyone being ready and START!
+const COUNTDOWN_SECS: u8 = 3;
+
+// matches are best of this many rounds unless told otherwise
+const DEFAULT_ROUNDS: u32 = 1;
+const MAX_ROUNDS: u32 = 15;
+// seconds between one round ending and the next one's START!
+const ROUND_BREAK_SECS: u8 = 5;
+// seconds between asking to resume a paused game and the boards going again
+const RESUME_SECS: u8 = 3;
+
+
+// things the connection threads tell the game loop about
+enum Event {
+  Connected(usize, mpsc::Sender<Message<'static>>),
+  Spectating(usize, mpsc::Sender<Message<'static>>), // connected to SPECTATE_PATH
+  Frame(usize, Frame),
+  Disconnected(usize)
+}
+
+struct Player {
+  conn_id: usize, // id of the connection thread this player is on
+  token: i32, // token identifier handed out in NUM_CONNS
+  sender: mpsc::Sender<Message<'static>>, // feeds this player's send loop
+  acked: bool, // true once we got CLIENT_ACK back
+  name: String,
+  ready: bool, // said they're ready to start
+  version: u8, // protocol version we talk to this player in
+  confirmed: u64, // last tick they confirmed in a lockstep game
+  game_over: Option<i32>, // who their boards say won this round, once they've said
+  dropped: Option<Instant>, // when their connection dropped, if it has
+  missed: Vec<Message<'static>> // what they've missed since
+}
+
+impl Player {
+  // anything for a dropped player waits for them to come back
+  fn send(&mut self, message: &ServerMessage) {
+    let message = frame_message(message.encode(self.version));
+    if self.dropped.is_some() {
+      self.missed.push(message);
+    } else {
+      let _ = self.sender.send(message);
+    }
+  }
+}
+
+// somebody watching. they get sent everything the players do but don't get a board,
+// and nothing they send gets passed on
+struct Spectator {
+  conn_id: usize,
+  sender: mpsc::Sender<Message<'static>>,
+  acked: bool, // nothing gets sent to them until they've said what version they speak
+  version: u8
+}
+
+impl Spectator {
+  fn send(&self, message: &ServerMessage) {
+    let _ = self.sender.send(frame_message(message.encode(self.version)));
+  }
+}
+
+struct Game {
+  num_players: usize, // game starts once this many players have acked
+  players: Vec<Player>,
+  spectators: Vec<Spectator>,
+  started: bool,
+  countdown: Option<(u8, Instant)>, // seconds to go until START!, and since when
+  // connections to a game that's already started. they get one message to REJOIN
+  rejoining: Vec<(usize, mpsc::Sender<Message<'static>>)>,
+  seed: u64,
+  // every input, resync, leave and game over in this round of a lockstep game, for
+  // spectators who turn up late to play forward from the start
+  history: Vec<ServerMessage>,
+
+  // authoritative games are played out here and clients just get sent the boards.
+  // otherwise every client runs every board and we only pass messages along
+  authoritative: bool,
+  boards: Vec<Board>,
+  sent: Vec<Option<Snapshot>>, // what each board looked like the last time we sent it
+  winner: Option<usize>, // of this round
+  paused: bool, // the boards are, until the countdown runs out
+
+  rounds: u32, // a match is best of this many
+  round: u32, // rounds started so far this match, 0 in the lobby
+  wins: Vec<u32> // rounds each player has won this match
+}
+
+impl Game {
+  fn new(num_players: usize, authoritative: bool, rounds: u32) -> Game {
+    Game {
+      num_players: num_players,
+      players: Vec::new(),
+      spectators: Vec::new(),
+      started: false,
+      countdown: None,
+      rejoining: Vec::new(),
+      seed: 0,
+      history: Vec::new(),
+      authoritative: authoritative,
+      boards: Vec::new(),
+      sent: Vec::new(),
+      winner: None,
+      paused: false,
+      rounds: rounds,
+      round: 0,
+      wins: vec![0; num_players]
+    }
+  }
+
+  fn handle_event(&mut self, event: Event) {
+    match event {
+      Event::Connected(conn_id, sender) => {
+        // could be somebody coming back, even if we haven't noticed they'd gone yet
+        if self.started {
+          self.rejoining.push((conn_id, sender));
+          return;
+        }
+        if self.players.len() >= self.num_players {
+          println!("Game is full, turning away connection {}", conn_id);
+          let _ = sender.send(Message::close());
+          return;
+        }
+        // hand out the lowest token that isn't taken yet
+        let mut token = 1;
+        while self.players.iter().any(|p| p.token == token) {
+          token += 1;
+        }
+        self.players.push(Player {
+          conn_id: conn_id,
+          token: token,
+          sender: sender,
+          acked: false,
+          name: format!("Player{}", token),
+          ready: false,
+          version: protocol::LEGACY_VERSION,
+          confirmed: lockstep::INPUT_DELAY,
+          game_over: None,
+          dropped: None,
+          missed: Vec::new()
+        });
+        println!("Player {} connected", token);
+        let num_players = self.num_players;
+        self.send_to(token, ServerMessage::NumConns { num_players: num_players, token: token });
+      },
+      Event::Spectating(conn_id, sender) => {
+        println!("Spectator connected on connection {}", conn_id);
+        let spectator = Spectator {
+          conn_id: conn_id,
+          sender: sender,
+          acked: false,
+          version: protocol::LEGACY_VERSION
+        };
+        spectator.send(&ServerMessage::Spectating(self.num_players));
+        self.spectators.push(spectator);
+        if self.legacy() {
+          self.turn_away_spectators();
+        }
+      },
+      Event::Frame(conn_id, frame) => {
+        if let Some(i) = self.spectators.iter().position(|s| s.conn_id == conn_id) {
+          self.handle_spectator_message(i, frame);
+          return;
+        }
+        if let Some(i) = self.rejoining.iter().position(|&(id, _)| id == conn_id) {
+          let (_, sender) = self.rejoining.remove(i);
+          self.rejoin(conn_id, sender, frame);
+          return;
+        }
+        let token = match self.players.iter().find(|p| p.conn_id == conn_id && p.dropped.is_none()) {
+          Some(p) => p.token,
+          None => return
+        };
+        self.handle_message(token, frame);
+      },
+      Event::Disconnected(conn_id) => {
+        self.rejoining.retain(|&(id, _)| id != conn_id);
+        if let Some(i) = self.spectators.iter().position(|s| s.conn_id == conn_id) {
+          println!("Spectator on connection {} left", conn_id);
+          self.spectators.remove(i);
+          return;
+        }
+        if let Some(i) = self.players.iter().position(|p| p.conn_id == conn_id && p.dropped.is_none()) {
+          if self.started {
+            // keep their place for a while in case they come back
+            println!("Player {} dropped, holding their place for {}s", self.players[i].token, REJOIN_GRACE_SECS);
+            self.players[i].dropped = Some(Instant::now());
+          } else {
+            let player = self.players.remove(i);
+            println!("Player {} disconnected", player.token);
+            self.broadcast_lobby();
+            self.check_ready();
+          }
+        }
+        // everybody left, so let a new game start
+        if self.players.is_empty() {
+          self.started = false;
+          self.round = 0;
+        }
+      }
+    }
+  }
+
+  // a new connection to a game in progress, which had better be a dropped player
+  // coming back
+  fn rejoin(&mut self, conn_id: usize, sender: mpsc::Sender<Message<'static>>, frame: Frame) {
+    let (token, version) = match ClientMessage::decode(&frame) {
+      Ok(ClientMessage::Rejoin { token, version }) => (token, cmp::min(version, protocol::PROTOCOL_VERSION)),
+      _ => {
+        println!("Game is full, turning away connection {}", conn_id);
+        let _ = sender.send(Message::close());
+        return;
+      }
+    };
+    let player = match self.players.iter_mut().find(|p| p.token == token) {
+      Some(p) => p,
+      None => {
+        println!("Error: Connection {}: Player {} isn't in the game", conn_id, token);
+        let _ = sender.send(Message::close());
+        return;
+      }
+    };
+    // the client can notice a dead connection before we do. the new one takes over,
+    // and anything still queued up for the old one goes nowhere
+    if player.dropped.is_none() {
+      println!("Player {} reconnected before their old connection dropped", token);
+      let _ = player.sender.send(Message::close());
+    }
+    println!("Player {} rejoined", token);
+    player.conn_id = conn_id;
+    player.sender = sender;
+    player.version = version;
+    player.dropped = None;
+    // lockstep clients still have their boards, they just need to catch up on what
+    // everyone did while they were gone. authoritative ones get sent the boards as
+    // they are now
+    let missed: Vec<Message<'static>> = player.missed.drain(..).collect();
+    if self.authoritative {
+      for (i, board) in self.boards.iter().enumerate() {
+        player.send(&ServerMessage::State(i as i32 + 1, Box::new(board.snapshot())));
+      }
+      if let Some(winner) = self.winner {
+        player.send(&ServerMessage::Relay(ClientMessage::GameOver(winner as i32 + 1)));
+      }
+    } else {
+      for message in missed {
+        let _ = player.sender.send(message);
+      }
+    }
+  }
+
+  // spectators only ever get to say hello
+  fn handle_spectator_message(&mut self, i: usize, frame: Frame) {
+    let conn_id = self.spectators[i].conn_id;
+    let version = match ClientMessage::decode(&frame) {
+      Ok(ClientMessage::Ack { token: protocol::SPECTATOR_TOKEN, version, .. }) =>
+        cmp::min(version, protocol::PROTOCOL_VERSION),
+      Ok(_) => {
+        println!("Error: Spectator {}: spectators can't send {:?}", conn_id, frame);
+        return;
+      },
+      Err(e) => {
+        println!("Error: Spectator {}: {}: {:?}", conn_id, e, frame);
+        return;
+      }
+    };
+    if self.spectators[i].acked {
+      println!("Error: Spectator {}: already acked: {:?}", conn_id, frame);
+      return;
+    }
+    if version != protocol::LEGACY_VERSION {
+      self.spectators[i].send(&ServerMessage::Version(version));
+    }
+    self.spectators[i].version = version;
+    self.spectators[i].acked = true;
+
+    // catch them up on whatever they've missed
+    let spectator = &self.spectators[i];
+    if !self.started {
+      spectator.send(&ServerMessage::Lobby(self.lobby()));
+      return;
+    }
+    spectator.send(&ServerMessage::Start { seed: Some(self.seed), authoritative: self.authoritative });
+    if self.authoritative {
+      // the boards as they are now
+      for (i, board) in self.boards.iter().enumerate() {
+        spectator.send(&ServerMessage::State(i as i32 + 1, Box::new(board.snapshot())));
+      }
+      if let Some(winner) = self.winner {
+        spectator.send(&ServerMessage::Relay(ClientMessage::GameOver(winner as i32 + 1)));
+      }
+    } else {
+      // every board from the start. they can play it all forward in one go once
+      // they know how far everybody's got
+      for message in self.history.iter() {
+        spectator.send(message);
+      }
+      for player in self.players.iter() {
+        spectator.send(&ServerMessage::Relay(ClientMessage::Confirm(player.token, player.confirmed)));
+      }
+    }
+  }
+
+  // everyone who's acked, and whether they're ready
+  fn lobby(&self) -> Vec<LobbyPlayer> {
+    let mut players: Vec<LobbyPlayer> = self.players.iter().filter(|p| p.acked).map(|p| {
+      LobbyPlayer { token: p.token, name: p.name.clone(), ready: p.ready }
+    }).collect();
+    players.sort_by_key(|p| p.token);
+    players
+  }
+
+  fn broadcast_lobby(&mut self) {
+    if self.started {
+      return
+    }
+    let players = self.lobby();
+    self.broadcast(None, ServerMessage::Lobby(players));
+  }
+
+  // starts the countdown once every place is taken and everybody's ready, and calls
+  // it off if that stops being true
+  fn check_ready(&mut self) {
+    if self.started {
+      return
+    }
+    let ready = self.players.len() == self.num_players && self.players.iter().all(|p| p.acked && p.ready);
+    match (ready, self.countdown.is_some()) {
+      (true, false) => {
+        self.countdown = Some((COUNTDOWN_SECS, Instant::now()));
+        self.broadcast(None, ServerMessage::Countdown(COUNTDOWN_SECS));
+      },
+      (false, true) => {
+        // the lobby going back out tells clients the countdown's off
+        self.countdown = None;
+        self.broadcast_lobby();
+      },
+      _ => ()
+    }
+  }
+
+  fn count_down(&mut self) {
+    let (seconds, since) = match self.countdown {
+      Some(countdown) => countdown,
+      None => return
+    };
+    let second = Duration::from_secs(1);
+    if since.elapsed() < second {
+      return
+    }
+    if seconds > 1 {
+      self.countdown = Some((seconds - 1, since + second));
+      self.broadcast(None, ServerMessage::Countdown(seconds - 1));
+    } else {
+      self.countdown = None;
+      if self.paused {
+        self.resume();
+      } else {
+        self.start();
+      }
+    }
+  }
+
+  // starts the next round, or the first one of a new match
+  fn start(&mut self) {
+    // every client seeds its boards with this so they all see the same pieces
+    let seed: u64 = rand::random();
+    if self.round == 0 {
+      self.wins = vec![0; self.num_players];
+    }
+    self.round += 1;
+    println!("START! Round {} (seed {})", self.round, seed);
+    self.started = true;
+    self.seed = seed;
+    self.winner = None;
+    self.paused = false;
+    self.history.clear();
+    // nobody's boards have got anywhere in this round yet
+    for player in self.players.iter_mut() {
+      player.confirmed = lockstep::INPUT_DELAY;
+      player.game_over = None;
+    }
+    // anybody who's left since the last round is out of this one from the start
+    let gone: Vec<i32> = (1..(self.num_players as i32 + 1))
+      .filter(|&t| !self.players.iter().any(|p| p.token == t))
+      .collect();
+    if self.authoritative {
+      while self.boards.len() < self.num_players {
+        self.boards.push(Board::with_seed(seed));
+      }
+      for board in self.boards.iter_mut() {
+        board.reset(seed);
+      }
+      for &token in gone.iter() {
+        self.boards[(token - 1) as usize].state = GameState::Over;
+      }
+      self.sent = vec![None; self.num_players];
+    } else {
+      // clients still know who left, but spectators who join later won't
+      for &token in gone.iter() {
+        self.history.push(ServerMessage::Left(token, 0));
+      }
+    }
+    let authoritative = self.authoritative;
+    self.broadcast(None, ServerMessage::Start { seed: Some(seed), authoritative: authoritative });
+  }
+
+  // somebody asked for the boards to go again and the countdown's done. they go out
+  // as Playing on the next tick
+  fn resume(&mut self) {
+    println!("Resuming");
+    self.paused = false;
+    for board in self.boards.iter_mut() {
+      board.pause(false);
+    }
+  }
+
+  // `winner` won the round. on to the next one, or back to the lobby for a rematch if
+  // that settles the match
+  fn round_over(&mut self, winner: usize) {
+    self.winner = Some(winner);
+    // a round can end while it's paused, if whoever paused it never comes back. the
+    // next countdown is for the next round, not for resuming this one
+    self.paused = false;
+    self.wins[winner] += 1;
+    let match_over = self.wins[winner] > self.rounds / 2;
+    println!("Player {} wins round {} ({})", winner + 1, self.round,
+             self.wins.iter().map(|w| w.to_string()).collect::<Vec<String>>().join("-"));
+    let wins = self.wins.clone();
+    self.broadcast(None, ServerMessage::RoundOver { winner: winner as i32 + 1, match_over: match_over, wins: wins });
+    if !match_over {
+      self.countdown = Some((ROUND_BREAK_SECS, Instant::now()));
+      self.broadcast(None, ServerMessage::Countdown(ROUND_BREAK_SECS));
+      return;
+    }
+    // everybody says whether they're up for another one
+    println!("Player {} wins the match", winner + 1);
+    self.started = false;
+    self.round = 0;
+    for player in self.players.iter_mut() {
+      player.ready = player.version == protocol::LEGACY_VERSION;
+    }
+    self.broadcast_lobby();
+    self.check_ready();
+  }
+
+  // gives up on players who've been gone too long
+  fn drop_expired(&mut self) {
+    let grace = Duration::from_secs(REJOIN_GRACE_SECS);
+    let mut expired = Vec::new();
+    self.players.retain(|p| {
+      match p.dropped {
+        Some(dropped) if dropped.elapsed() >= grace => {
+          expired.push((p.token, p.confirmed));
+          false
+        },
+        _ => true
+      }
+    });
+    let any_expired = !expired.is_empty();
+    for (token, confirmed) in expired {
+      println!("Player {} didn't come back", token);
+      if self.authoritative && self.started {
+        self.boards[(token - 1) as usize].state = GameState::Over;
+      }
+      // everything they sent has been passed on, so everyone can stop their board
+      // after the last tick they confirmed
+      if !self.authoritative {
+        self.history.push(ServerMessage::Left(token, confirmed));
+      }
+      self.broadcast(None, ServerMessage::Left(token, confirmed));
+    }
+    if self.players.is_empty() {
+      self.started = false;
+      self.round = 0;
+    } else if any_expired {
+      // whoever's still here might all agree on a winner now
+      self.check_game_over();
+    }
+  }
+
+  fn handle_message(&mut self, token: i32, frame: Frame) {
+    let message = match ClientMessage::decode(&frame) {
+      Ok(m) => m,
+      Err(e) => {
+        println!("Error: Player {}: {}: {:?}", token, e, frame);
+        return;
+      }
+    };
+    if let Err(e) = self.check_message(token, &message) {
+      println!("Error: Player {}: {}: {:?}", token, e, frame);
+      return;
+    }
+    match message {
+      ClientMessage::Ack { version, name, .. } => {
+        // talk the newest version we both know. old clients don't send a version and
+        // don't know about VERSION, so they just stay on v0
+        let version = cmp::min(version, protocol::PROTOCOL_VERSION);
+        // v0 clients pick their own pieces and run their own gravity, so nobody else
+        // can run their boards. they only get to play each other
+        let legacy = version == protocol::LEGACY_VERSION;
+        let mixed = self.players.iter().any(|p| p.acked && p.token != token && (p.version == protocol::LEGACY_VERSION) != legacy);
+        if mixed || (legacy && self.authoritative) {
+          println!("Player {} speaks v{}, which can't play in this game, turning them away", token, version);
+          if let Some(p) = self.players.iter().find(|p| p.token == token) {
+            let _ = p.sender.send(Message::close());
+          }
+          return
+        }
+        if legacy {
+          self.turn_away_spectators();
+        }
+        if version != protocol::LEGACY_VERSION {
+          // still in v0 here, the client switches once it reads this
+          self.send_to(token, ServerMessage::Version(version));
+        }
+        if let Some(p) = self.players.iter_mut().find(|p| p.token == token) {
+          p.acked = true;
+          p.version = version;
+          if let Some(name) = name {
+            println!("Player {} is {}", token, name);
+            p.name = name;
+          }
+          // old clients don't know about READY, so they're ready whenever
+          p.ready = version == protocol::LEGACY_VERSION;
+        }
+        self.broadcast_lobby();
+        self.check_ready();
+      },
+      ClientMessage::Ready(_, ready) => {
+        if self.started {
+          return
+        }
+        if let Some(p) = self.players.iter_mut().find(|p| p.token == token) {
+          p.ready = ready;
+        }
+        self.broadcast_lobby();
+        self.check_ready();
+      },
+      ClientMessage::Input(_, _, action) | ClientMessage::LegacyInput(_, action) if self.authoritative => {
+        // straight onto the board, whatever tick the client thought it was. the board
+        // gets sent back out on the next tick
+        if self.started && self.winner.is_none() && !self.paused {
+          self.boards[(token - 1) as usize].apply(action);
+        }
+      },
+      ClientMessage::Pause(_, _, paused) if self.authoritative => {
+        if !self.started || self.winner.is_some() {
+          return
+        }
+        if paused && !self.paused {
+          println!("Player {} paused the game", token);
+          self.paused = true;
+          self.countdown = None;
+          for board in self.boards.iter_mut() {
+            board.pause(true);
+          }
+        } else if !paused && self.paused && self.countdown.is_none() {
+          println!("Player {} resumed the game", token);
+          self.countdown = Some((RESUME_SECS, Instant::now()));
+        } else {
+          return
+        }
+        self.broadcast(None, ServerMessage::Relay(message));
+        if !paused {
+          self.broadcast(None, ServerMessage::Countdown(RESUME_SECS));
+        }
+      },
+      // clients don't run the boards in authoritative games
+      ClientMessage::Confirm(..) | ClientMessage::Checksum(..) if self.authoritative => (),
+      ClientMessage::Rejoin { .. } => {
+        println!("Error: Player {}: already in the game: {:?}", token, frame);
+      },
+      _ if self.authoritative => {
+        println!("Error: Player {}: server decides garbage and game over: {:?}", token, frame);
+      },
+      _ => {
+        // anything that turns up between rounds is about the one that just ended
+        if !self.started || self.winner.is_some() {
+          return
+        }
+        match message {
+          ClientMessage::Confirm(_, tick) => {
+            if let Some(p) = self.players.iter_mut().find(|p| p.token == token) {
+              p.confirmed = cmp::max(p.confirmed, tick);
+            }
+          },
+          ClientMessage::Input(..) | ClientMessage::Pause(..) | ClientMessage::Resync(..) => {
+            self.history.push(ServerMessage::Relay(message.clone()));
+          },
+          ClientMessage::GameOver(winner) => {
+            if let Some(p) = self.players.iter_mut().find(|p| p.token == token) {
+              p.game_over = Some(winner);
+            }
+            self.check_game_over();
+            return
+          },
+          _ => ()
+        }
+        self.broadcast(Some(token), ServerMessage::Relay(message));
+      }
+    }
+  }
+
+  // runs the boards one tick in an authoritative game, or keeps time in a lockstep one
+  fn tick(&mut self) {
+    self.drop_expired();
+    self.count_down();
+    // lockstep clients keep their own time
+    if !self.started || self.winner.is_some() || !self.authoritative {
+      return
+    }
+    for board in self.boards.iter_mut() {
+      board.tick();
+    }
+
+    for i in 0..self.boards.len() {
+      let attack = self.boards[i].take_attack();
+      if attack == 0 {
+        continue
+      }
+      if let Some(target) = board::garbage_target(&self.boards, i) {
+        let hole_col = rand::thread_rng().gen_range(0, BOARD_WIDTH as usize);
+        self.boards[target].queue_garbage(attack, hole_col);
+      }
+    }
+
+    // only send boards that visibly changed. the timers tick along every frame
+    // but clients don't run the boards, so they don't care
+    for i in 0..self.boards.len() {
+      let snapshot = self.boards[i].snapshot();
+      let mut view = snapshot.clone();
+      view.gravity_timer = 0.0;
+      view.lock_timer = 0.0;
+      if self.sent[i].as_ref() != Some(&view) {
+        self.sent[i] = Some(view);
+        self.broadcast(None, ServerMessage::State(i as i32 + 1, Box::new(snapshot)));
+      }
+    }
+
+    if let Some(winner) = board::winner(&self.boards) {
+      self.broadcast(None, ServerMessage::Relay(ClientMessage::GameOver(winner as i32 + 1)));
+      self.round_over(winner);
+    }
+  }
+
+  // every client runs every board, so they should all come up with the same winner.
+  // the round's over once everyone still in it has said who
+  fn check_game_over(&mut self) {
+    if !self.started || self.authoritative || self.winner.is_some() {
+      return
+    }
+    let mut votes = Vec::new();
+    for player in self.players.iter() {
+      match player.game_over {
+        Some(winner) => votes.push(winner),
+        None => return
+      }
+    }
+    // they can only disagree if somebody's boards drifted. most votes wins, and the
+    // lowest token if that's a tie
+    let mut counts = vec![0; self.num_players];
+    for &winner in votes.iter() {
+      counts[(winner - 1) as usize] += 1;
+    }
+    let most = counts.iter().cloned().max().unwrap_or(0);
+    let winner = match counts.iter().position(|&c| c == most) {
+      Some(i) if most > 0 => i,
+      _ => return
+    };
+    if counts[winner] != votes.len() {
+      println!("Error: players don't agree who won round {}: {:?}", self.round, votes);
+    }
+    // spectators who turn up later need to know how far everyone got before they
+    // hear who won, same as everyone watching now
+    for player in self.players.iter() {
+      self.history.push(ServerMessage::Relay(ClientMessage::Confirm(player.token, player.confirmed)));
+    }
+    let message = ServerMessage::Relay(ClientMessage::GameOver(winner as i32 + 1));
+    self.history.push(message.clone());
+    self.broadcast(None, message);
+    self.round_over(winner);
+  }
+
+  // a game of v0 clients is just them passing their key presses and pieces to each
+  // other. spectators take their pieces from the seed, so they'd see a different game
+  fn legacy(&self) -> bool {
+    self.players.iter().any(|p| p.acked && p.version == protocol::LEGACY_VERSION)
+  }
+
+  fn turn_away_spectators(&mut self) {
+    for spectator in self.spectators.drain(..) {
+      println!("Turning away the spectator on connection {}, games of v0 clients can't be watched", spectator.conn_id);
+      let _ = spectator.sender.send(Message::close());
+    }
+  }
+
+  // players can only speak for themselves, and only about boards that are in the game
+  fn check_message(&self, token: i32, message: &ClientMessage) -> Result<()> {
+    match *message {
+      ClientMessage::GameOver(winner) => {
+        protocol::board_index(winner, self.num_players)?;
+      },
+      ClientMessage::Garbage { to, .. } => {
+        protocol::board_index(to, self.num_players)?;
+      },
+      ClientMessage::Desync(_, board) => {
+        protocol::board_index(board, self.num_players)?;
+      },
+      _ => { }
+    }
+    match *message {
+      ClientMessage::GameOver(_) => Ok(()),
+      _ if message.token() != token => Err(format!("message is for Player {}", message.token())),
+      _ => Ok(())
+    }
+  }
+
+  fn send_to(&mut self, token: i32, message: ServerMessage) {
+    for player in self.players.iter_mut() {
+      if player.token == token {
+        player.send(&message);
+      }
+    }
+  }
+
+  // send to every player except `except`, each in their own protocol version, and
+  // to every spectator
+  fn broadcast(&mut self, except: Option<i32>, message: ServerMessage) {
+    for player in self.players.iter_mut() {
+      if Some(player.token) == except {
+        continue
+      }
+      // v0 clients run their own boards their own way and wouldn't know what to
+      // make of the lockstep bookkeeping
+      if player.version == protocol::LEGACY_VERSION && lockstep_only(&message) {
+        continue
+      }
+      // and newer ones pick their pieces from the seed
+      if player.version != protocol::LEGACY_VERSION && legacy_only(&message) {
+        continue
+      }
+      player.send(&message);
+    }
+    if legacy_only(&message) {
+      return
+    }
+    for spectator in self.spectators.iter().filter(|s| s.acked) {
+      spectator.send(&message);
+    }
+  }
+}
+
+fn lockstep_only(message: &ServerMessage) -> bool {
+  matches!(*message, ServerMessage::Relay(ClientMessage::Confirm(..)) |
+                     ServerMessage::Relay(ClientMessage::Checksum(..)) |
+                     ServerMessage::Relay(ClientMessage::Desync(..)) |
+                     ServerMessage::Relay(ClientMessage::Resync(..)) |
+                     ServerMessage::Relay(ClientMessage::Pause(..)))
+}
+
+fn legacy_only(message: &ServerMessage) -> bool {
+  matches!(*message, ServerMessage::Relay(ClientMessage::FirstBlock(..)) |
+                     ServerMessage::Relay(ClientMessage::NewBlock(..)))
+}
+
+fn frame_message(frame: Frame) -> Message<'static> {
+  match frame {
+    Frame::Text(text) => Message::text(text),
+    Frame::Binary(bytes) => Message::binary(bytes)
+  }
+}
+
+// when tick n is due, counting from when the server started. worked out from
+// scratch each time so the odd nanoseconds in a 60th of a second don't add up
+fn tick_time(n: u64) -> Duration {
+  Duration::new(n / TICKS_PER_SECOND, ((n % TICKS_PER_SECOND) * 1_000_000_000 / TICKS_PER_SECOND) as u32)
+}
+
+// `tetris-server [number of players] [--authoritative] [--rounds N]`
+fn settings_from_args() -> (usize, bool, u32, String) {
+  let mut num_players = DEFAULT_NUM_PLAYERS;
+  let mut authoritative = false;
+  let mut rounds = DEFAULT_ROUNDS;
+  let mut addr = DEFAULT_ADDR.to_string();
+  let mut args = env::args().skip(1);
+  while let Some(arg) = args.next() {
+    if arg == "--authoritative" {
+      authoritative = true;
+      continue
+    }
+    if arg == "--listen" {
+      match args.next() {
+        Some(a) => addr = a,
+        None => {
+          println!("Error: --listen needs an address, like {}", DEFAULT_ADDR);
+          process::exit(1);
+        }
+      }
+      continue
+    }
+    if arg == "--rounds" {
+      let n = args.next().unwrap_or_default();
+      match n.parse::<u32>() {
+        Ok(n) if (1..=MAX_ROUNDS).contains(&n) => rounds = n,
+        _ => {
+          println!("Error: --rounds must be between 1 and {}, got {}", MAX_ROUNDS, n);
+          process::exit(1);
+        }
+      }
+      continue
+    }
+    match arg.parse::<usize>() {
+      Ok(n) if (2..=MAX_PLAYERS).contains(&n) => num_players = n,
+      _ => {
+        println!("Error: number of players must be between 2 and {}, got {}", MAX_PLAYERS, arg);
+        process::exit(1);
+      }
+    }
+  }
+  (num_players, authoritative, rounds, addr)
+}
+
+fn main() {
+  let (num_players, authoritative, rounds, addr) = settings_from_args();
+  let server = match Server::bind(&addr[..]) {
+    Ok(s) => s,
+    Err(e) => {
+      println!("Error: can't listen on {}: {}", addr, e);
+      process::exit(1);
+    }
+  };
+  println!("Listening on {}, waiting for {} players", addr, num_players);
+  if authoritative {
+    println!("Running the boards on the server");
+  }
+  if rounds > 1 {
+    println!("Matches are best of {}", rounds);
+  }
+
+  let (tx, rx) = channel();
+
+  // game loop: owns all of the game state, connection threads just feed it events
+  thread::spawn(move || {
+    let mut game = Game::new(num_players, authoritative, rounds);
+    let started = Instant::now();
+    let mut ticks: u64 = 0;
+    loop {
+      // wait for an event, but not past the next tick
+      let wait = tick_time(ticks + 1).checked_sub(started.elapsed()).unwrap_or(Duration::from_millis(0));
+      match rx.recv_timeout(wait) {
+        Ok(event) => game.handle_event(event),
+        Err(RecvTimeoutError::Timeout) => (),
+        Err(RecvTimeoutError::Disconnected) => return
+      }
+      // catch up on every tick that's gone by, so the boards keep to the clock
+      while started.elapsed() >= tick_time(ticks + 1) {
+        ticks += 1;
+        game.tick();
+      }
+    }
+  });
+
+  for (i, connection) in server.enumerate() {
+    let id = i + 1;
+    let tx_events = tx.clone();
+
+    thread::spawn(move || {
+      let request = match connection {
+        Ok(c) => match c.read_request() {
+          Ok(r) => r,
+          Err(e) => {
+            println!("Error: Connection {}: {:?}", id, e);
+            return;
+          }
+        },
+        Err(e) => {
+          println!("Error: Connection {}: {:?}", id, e);
+          return;
+        }
+      };
+      if let Err(e) = request.validate() {
+        println!("Error: Connection {}: {:?}", id, e);
+        return;
+      }
+      let spectating = request.url.to_string() == protocol::SPECTATE_PATH;
+      let client = match request.accept().send() {
+        Ok(c) => c,
+        Err(e) => {
+          println!("Error: Connection {}: {:?}", id, e);
+          return;
+        }
+      };
+      let (mut sender, mut receiver) = client.split();
+      let (tx_out, rx_out) = channel();
+
+      let tx_out_1 = tx_out.clone();
+      thread::spawn(move || {
+        loop {
+          // Send loop
+          let message: Message = match rx_out.recv() {
+            Ok(m) => m,
+            Err(_) => return
+          };
+          if message.opcode == Type::Close {
+            let _ = sender.send_message(&message);
+            return;
+          }
+          match sender.send_message(&message) {
+            Ok(()) => (),
+            Err(e) => {
+              println!("Error: Send Loop {}: {:?}", id, e);
+              let _ = sender.send_message(&Message::close());
+              return;
+            }
+          }
+        }
+      });
+
+      if spectating {
+        let _ = tx_events.send(Event::Spectating(id, tx_out));
+      } else {
+        let _ = tx_events.send(Event::Connected(id, tx_out));
+      }
+
+      // Receive loop
+      for message in receiver.incoming_messages() {
+        let message: Message = match message {
+          Ok(m) => m,
+          Err(e) => {
+            println!("Error: Receive Loop {}: {:?}", id, e);
+            break;
+          }
+        };
+        match message.opcode {
+          Type::Close => {
+            let _ = tx_out_1.send(Message::close());
+            break;
+          },
+          Type::Ping => {
+            let _ = tx_out_1.send(Message::pong(message.payload.into_owned()));
+          },
+          Type::Binary => {
+            let _ = tx_events.send(Event::Frame(id, Frame::Binary(message.payload.into_owned())));
+          },
+          _ => {
+            match String::from_utf8(message.payload.into_owned()) {
+              Ok(text) => {
+                let _ = tx_events.send(Event::Frame(id, Frame::Text(text)));
+              },
+              Err(_) => {
+                println!("Error: Receive Loop {}: message was not utf-8", id);
+              }
+            }
+          }
+        }
+      }
+      let _ = tx_events.send(Event::Disconnected(id));
+    });
+  }
+}
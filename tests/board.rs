@@ -0,0 +1,527 @@
+extern crate tetris;
+
+use tetris::{BOARD_WIDTH, BOARD_HEIGHT};
+use tetris::block::{Shape, Tetromino};
+use tetris::board;
+use tetris::board::{Action, Board, Cell, GameState};
+
+// board whose current piece is `shape`, fresh at its spawn position
+fn board_with_piece(shape: Shape) -> Board {
+  let mut board = Board::with_seed(0);
+  board.current_piece = Tetromino::spawn(shape);
+  board.update_ghost_piece();
+  board
+}
+
+// fill a whole row except for the given columns
+fn fill_row_except(board: &mut Board, row: usize, gaps: &[usize]) {
+  for col in 0..BOARD_WIDTH as usize {
+    if !gaps.contains(&col) {
+      board.set_cell(row, col, Cell::Garbage);
+    }
+  }
+}
+
+// board coordinates (row, col) of every block in the current piece
+fn piece_cells(board: &Board) -> Vec<(i64, i64)> {
+  let piece = board.current_piece;
+  let mut cells = Vec::new();
+  for row in 0..4 {
+    for col in 0..4 {
+      if piece.blocks[row][col] == 1 {
+        cells.push((row as i64 + piece.y_offset as i64, col as i64 + piece.x_offset as i64));
+      }
+    }
+  }
+  cells
+}
+
+#[test]
+fn piece_stops_at_walls() {
+  let mut board = board_with_piece(Shape::O);
+  for _ in 0..BOARD_WIDTH {
+    board.apply(Action::MoveLeft);
+  }
+  assert!(piece_cells(&board).iter().all(|&(_, col)| col >= 0));
+  assert!(piece_cells(&board).iter().any(|&(_, col)| col == 0));
+
+  for _ in 0..BOARD_WIDTH {
+    board.apply(Action::MoveRight);
+  }
+  assert!(piece_cells(&board).iter().any(|&(_, col)| col == BOARD_WIDTH - 1));
+}
+
+#[test]
+fn piece_stops_at_locked_cells() {
+  let mut board = board_with_piece(Shape::O);
+  // O spawns in columns 4 and 5, so this is right next to it
+  board.set_cell(1, 3, Cell::Garbage);
+  let x = board.current_piece.x_offset;
+  board.apply(Action::MoveLeft);
+  assert_eq!(board.current_piece.x_offset, x);
+  board.apply(Action::MoveRight);
+  assert_eq!(board.current_piece.x_offset, x + 1.0);
+}
+
+#[test]
+fn soft_drop_stops_on_the_stack() {
+  let mut board = board_with_piece(Shape::O);
+  fill_row_except(&mut board, (BOARD_HEIGHT - 1) as usize, &[]);
+  for _ in 0..BOARD_HEIGHT {
+    board.apply(Action::SoftDrop);
+  }
+  // O is two tall and sits on the filled bottom row
+  assert_eq!(board.current_piece.y_offset, (BOARD_HEIGHT - 3) as f64);
+  assert_eq!(board.ghost_piece.y_offset, board.current_piece.y_offset);
+}
+
+#[test]
+fn rotating_four_times_gets_back_to_spawn() {
+  for shape in [Shape::I, Shape::O, Shape::T, Shape::S, Shape::Z, Shape::J, Shape::L].iter() {
+    let mut board = board_with_piece(*shape);
+    // move it down a bit so nothing needs to kick
+    for _ in 0..5 {
+      board.apply(Action::SoftDrop);
+    }
+    let before = board.current_piece;
+    for _ in 0..4 {
+      assert_eq!(board.rotate_current_piece(true), Some(0));
+    }
+    assert_eq!(board.current_piece.blocks, before.blocks);
+    assert_eq!(board.current_piece.rotation, 0);
+    for _ in 0..4 {
+      board.apply(Action::RotateCcw);
+    }
+    assert_eq!(board.current_piece.blocks, before.blocks);
+  }
+}
+
+#[test]
+fn rotation_kicks_off_the_wall() {
+  let mut board = board_with_piece(Shape::I);
+  for _ in 0..5 {
+    board.apply(Action::SoftDrop);
+  }
+  board.apply(Action::RotateCw);
+  for _ in 0..BOARD_WIDTH {
+    board.apply(Action::MoveLeft);
+  }
+  // vertical I flush against the left wall. rotating back to flat has to kick right
+  let kick = board.rotate_current_piece(false);
+  assert!(kick.is_some() && kick != Some(0));
+  assert!(piece_cells(&board).iter().all(|&(_, col)| (0..BOARD_WIDTH).contains(&col)));
+}
+
+#[test]
+fn rotation_fails_when_boxed_in() {
+  let mut board = board_with_piece(Shape::I);
+  for _ in 0..5 {
+    board.apply(Action::SoftDrop);
+  }
+  // fill everything around the flat I so no kick can work
+  let row = board.current_piece.y_offset as usize + 1;
+  for r in (row - 3)..(row + 4) {
+    if r != row {
+      fill_row_except(&mut board, r, &[]);
+    }
+  }
+  let before = board.current_piece;
+  assert_eq!(board.rotate_current_piece(true), None);
+  assert_eq!(board.current_piece.blocks, before.blocks);
+}
+
+#[test]
+fn clearing_a_line_shifts_the_stack_down() {
+  let mut board = board_with_piece(Shape::O);
+  let bottom = (BOARD_HEIGHT - 1) as usize;
+  fill_row_except(&mut board, bottom, &[4, 5]);
+  board.set_cell(bottom - 1, 0, Cell::Garbage);
+  board.apply(Action::HardDrop);
+
+  // bottom row cleared, the O's top half and the lone block fell into it
+  assert_eq!(board.cells[bottom][0], Cell::Garbage);
+  assert_eq!(board.cells[bottom][4], Cell::Block(Shape::O));
+  assert_eq!(board.cells[bottom][5], Cell::Block(Shape::O));
+  assert_eq!(board.cells[bottom][1], Cell::Empty);
+  assert!(board.cells[bottom - 1].iter().all(|&cell| cell == Cell::Empty));
+  assert_eq!(board.lines_cleared, 1);
+}
+
+// points for hard dropping the current piece from where it is
+fn hard_drop_points(board: &Board) -> u64 {
+  2 * (board.ghost_piece.y_offset - board.current_piece.y_offset) as u64
+}
+
+#[test]
+fn scores_per_line() {
+  // nothing for locking a piece, but 2 a row for hard dropping it and 1 for soft
+  let mut board = board_with_piece(Shape::O);
+  board.apply(Action::SoftDrop);
+  assert_eq!(board.score, 1);
+  let drop = hard_drop_points(&board);
+  board.apply(Action::HardDrop);
+  assert_eq!(board.score, 1 + drop);
+  assert_eq!(board.last_clear, None);
+
+  let mut board = board_with_piece(Shape::O);
+  fill_row_except(&mut board, (BOARD_HEIGHT - 1) as usize, &[4, 5]);
+  let drop = hard_drop_points(&board);
+  board.apply(Action::HardDrop);
+  assert_eq!(board.score, 100 + drop);
+
+  // times the level it happened on
+  let mut board = board_with_piece(Shape::O);
+  board.level = 3;
+  fill_row_except(&mut board, (BOARD_HEIGHT - 1) as usize, &[4, 5]);
+  fill_row_except(&mut board, (BOARD_HEIGHT - 2) as usize, &[4, 5]);
+  board.set_cell((BOARD_HEIGHT - 3) as usize, 0, Cell::Garbage); // so it's not an all clear
+  let drop = hard_drop_points(&board);
+  board.apply(Action::HardDrop);
+  assert_eq!(board.score, 300 * 3 + drop);
+  assert_eq!(board.last_clear.unwrap().name(), "DOUBLE");
+
+  // vertical I down a well in column 0, which leaves nothing behind
+  let mut board = board_with_piece(Shape::I);
+  for row in (BOARD_HEIGHT - 4)..BOARD_HEIGHT {
+    fill_row_except(&mut board, row as usize, &[0]);
+  }
+  board.apply(Action::RotateCw);
+  for _ in 0..BOARD_WIDTH {
+    board.apply(Action::MoveLeft);
+  }
+  let drop = hard_drop_points(&board);
+  board.apply(Action::HardDrop);
+  assert_eq!(board.score, 800 + 2000 + drop);
+  assert_eq!(board.lines_cleared, 4);
+  assert!(board.cells.iter().all(|row| row.iter().all(|&cell| cell == Cell::Empty)));
+  let clear = board.last_clear.unwrap();
+  assert!(clear.all_clear && clear.difficult());
+  assert_eq!(clear.name(), "TETRIS ALL CLEAR");
+  assert_eq!(board.take_attack(), 4 + 10);
+}
+
+// puts a T with its center at (row, col), pointing the way `rotation` says, then
+// rotates it clockwise once so it got where it is by rotating
+fn spin_t_into(board: &mut Board, row: usize, col: usize, rotation: usize) {
+  let mut piece = Tetromino::spawn(Shape::T);
+  for _ in 0..(rotation + 3) % 4 {
+    piece.rotate_right();
+  }
+  piece.x_offset = col as f64 - 1.0;
+  piece.y_offset = row as f64 - 1.0;
+  board.current_piece = piece;
+  board.update_ghost_piece();
+  assert_eq!(board.rotate_current_piece(true), Some(0));
+  assert_eq!(board.current_piece.rotation, rotation);
+}
+
+#[test]
+fn t_spins_go_by_the_3_corner_rule() {
+  let bottom = (BOARD_HEIGHT - 1) as usize;
+
+  // t-spin double: a T pointing down into a slot under an overhang
+  let mut board = Board::with_seed(0);
+  fill_row_except(&mut board, bottom, &[4]);
+  fill_row_except(&mut board, bottom - 1, &[3, 4, 5]);
+  board.set_cell(bottom - 2, 3, Cell::Garbage);
+  spin_t_into(&mut board, bottom - 1, 4, 2);
+  board.apply(Action::HardDrop);
+  let clear = board.last_clear.unwrap();
+  assert_eq!(clear.spin, board::Spin::Full);
+  assert_eq!(clear.lines, 2);
+  assert!(!clear.back_to_back);
+  assert_eq!(clear.name(), "T-SPIN DOUBLE");
+  assert_eq!(board.score, 1200);
+  assert_eq!(board.take_attack(), 4);
+
+  // a tetris right after is back-to-back, worth half again as much, and part of a combo
+  board.current_piece = Tetromino::spawn(Shape::I);
+  for row in (BOARD_HEIGHT - 4)..BOARD_HEIGHT {
+    fill_row_except(&mut board, row as usize, &[0]);
+  }
+  board.apply(Action::RotateCw);
+  for _ in 0..BOARD_WIDTH {
+    board.apply(Action::MoveLeft);
+  }
+  let drop = hard_drop_points(&board);
+  board.apply(Action::HardDrop);
+  let clear = board.last_clear.unwrap();
+  assert!(clear.back_to_back && clear.all_clear);
+  assert_eq!(clear.combo, 2);
+  assert_eq!(clear.name(), "BACK-TO-BACK TETRIS ALL CLEAR");
+  assert_eq!(board.score, 1200 + 1200 + 50 + 2000 + drop);
+  assert_eq!(board.take_attack(), 4 + 1 + 10);
+
+  // only one of the corners it points at is filled, so it's a mini
+  let mut board = Board::with_seed(0);
+  board.set_cell(bottom, 3, Cell::Garbage);
+  board.set_cell(bottom, 5, Cell::Garbage);
+  board.set_cell(bottom - 2, 3, Cell::Garbage);
+  spin_t_into(&mut board, bottom - 1, 4, 0);
+  board.apply(Action::HardDrop);
+  let clear = board.last_clear.unwrap();
+  assert_eq!(clear.spin, board::Spin::Mini);
+  assert_eq!(clear.name(), "T-SPIN MINI");
+  assert_eq!(board.score, 100);
+  assert_eq!(board.combo, 0);
+
+  // the same spot without rotating last is no spin at all
+  let mut board = Board::with_seed(0);
+  board.set_cell(bottom, 3, Cell::Garbage);
+  board.set_cell(bottom, 5, Cell::Garbage);
+  board.set_cell(bottom - 2, 3, Cell::Garbage);
+  spin_t_into(&mut board, bottom - 1, 4, 0);
+  board.apply(Action::MoveRight);
+  board.apply(Action::MoveLeft);
+  board.apply(Action::HardDrop);
+  assert_eq!(board.last_clear, None);
+  assert_eq!(board.score, 0);
+
+  // or rotated way up top and left for gravity to bring down into a spot like that
+  let mut board = Board::with_seed(0);
+  board.set_cell(bottom - 2, 0, Cell::Garbage);
+  board.set_cell(bottom, 0, Cell::Garbage);
+  board.set_cell(bottom, 2, Cell::Garbage);
+  spin_t_into(&mut board, 5, 1, 1);
+  for _ in 0..1000 {
+    if board.cells[bottom][1] != Cell::Empty {
+      break
+    }
+    board.update(0.1);
+  }
+  assert_eq!(board.cells[bottom][1], Cell::Block(Shape::T));
+  assert_eq!(board.last_clear, None);
+  assert_eq!(board.score, 0);
+}
+
+#[test]
+fn game_over_when_the_next_piece_cant_spawn() {
+  let mut board = board_with_piece(Shape::O);
+  // leave room for the O to lock but block where everything spawns
+  for row in 2..BOARD_HEIGHT as usize {
+    fill_row_except(&mut board, row, &[0]);
+  }
+  board.apply(Action::HardDrop);
+  assert_eq!(board.state, GameState::Over);
+
+  // dead boards ignore gravity, and the keys
+  let score = board.score;
+  board.update(10.0);
+  board.apply(Action::SoftDrop);
+  board.apply(Action::HardDrop);
+  assert_eq!(board.score, score);
+}
+
+#[test]
+fn game_over_when_garbage_pushes_the_stack_off_the_top() {
+  let mut board = Board::with_seed(0);
+  board.set_cell(3, 0, Cell::Garbage);
+  board.receive_garbage(4, 5);
+  assert_eq!(board.state, GameState::Over);
+}
+
+#[test]
+fn every_bag_has_one_of_each_piece() {
+  let mut board = Board::with_seed(42);
+  let mut pieces = vec![board.current_piece.name, board.next_piece.name];
+  while pieces.len() < 7 * 4 {
+    pieces.push(board.get_next_piece().name);
+  }
+  for bag in pieces.chunks(7) {
+    for shape in [Shape::I, Shape::O, Shape::T, Shape::S, Shape::Z, Shape::J, Shape::L].iter() {
+      assert_eq!(bag.iter().filter(|&s| s == shape).count(), 1);
+    }
+  }
+}
+
+#[test]
+fn same_seed_same_pieces() {
+  let mut a = Board::with_seed(7);
+  let mut b = Board::with_seed(7);
+  let mut c = Board::with_seed(8);
+  let mut a_pieces = Vec::new();
+  let mut c_pieces = Vec::new();
+  for _ in 0..50 {
+    let piece = a.get_next_piece().name;
+    assert_eq!(b.get_next_piece().name, piece);
+    a_pieces.push(piece);
+    c_pieces.push(c.get_next_piece().name);
+  }
+  // 50 pieces in the exact same order from another seed would be a big coincidence
+  assert!(a_pieces != c_pieces);
+}
+
+#[test]
+fn gravity_speeds_up_with_level() {
+  let mut board = Board::with_seed(0);
+  assert_eq!(board.level, 1);
+  let slow = board.gravity_interval();
+  board.level = 10;
+  assert!(board.gravity_interval() < slow);
+
+  let mut board = Board::with_seed(0);
+  let y = board.current_piece.y_offset;
+  board.update(slow / 2.0);
+  assert_eq!(board.current_piece.y_offset, y);
+  board.update(slow / 2.0);
+  assert_eq!(board.current_piece.y_offset, y + 1.0);
+}
+
+#[test]
+fn lock_delay_resets_on_moves() {
+  let mut board = board_with_piece(Shape::O);
+  board.apply(Action::SoftDrop);
+  while board.ghost_piece.y_offset != board.current_piece.y_offset {
+    board.apply(Action::SoftDrop);
+  }
+  board.update(board.lock_delay * 0.75);
+  board.apply(Action::MoveLeft);
+  board.update(board.lock_delay * 0.75);
+  // would have locked by now without the move
+  assert_eq!(board.current_piece.name, Shape::O);
+  board.update(board.lock_delay);
+  assert!(board.cells[(BOARD_HEIGHT - 1) as usize].contains(&Cell::Block(Shape::O)));
+}
+
+#[test]
+fn hold_swaps_once_per_piece() {
+  let mut board = Board::with_seed(3);
+  let first = board.current_piece.name;
+  let next = board.next_piece.name;
+  board.apply(Action::MoveLeft);
+  board.apply(Action::Hold);
+  assert_eq!(board.hold_piece.map(|p| p.name), Some(first));
+  assert_eq!(board.current_piece.name, next);
+
+  board.apply(Action::Hold);
+  assert_eq!(board.current_piece.name, next);
+
+  board.apply(Action::HardDrop);
+  board.apply(Action::Hold);
+  assert_eq!(board.current_piece.name, first);
+  assert_eq!(board.current_piece.x_offset, Tetromino::spawn(first).x_offset);
+}
+
+#[test]
+fn garbage_waits_for_the_next_lock() {
+  let mut board = board_with_piece(Shape::O);
+  board.queue_garbage(2, 3);
+  assert_eq!(board.pending_garbage_lines(), 2);
+  assert!(board.cells[(BOARD_HEIGHT - 1) as usize].iter().all(|&cell| cell == Cell::Empty));
+
+  board.apply(Action::HardDrop);
+  assert_eq!(board.pending_garbage_lines(), 0);
+  for row in (BOARD_HEIGHT - 2)..BOARD_HEIGHT {
+    for col in 0..BOARD_WIDTH as usize {
+      let expected = if col == 3 { Cell::Empty } else { Cell::Garbage };
+      assert_eq!(board.cells[row as usize][col], expected);
+    }
+  }
+}
+
+#[test]
+fn clears_cancel_incoming_garbage() {
+  let mut board = board_with_piece(Shape::O);
+  fill_row_except(&mut board, (BOARD_HEIGHT - 1) as usize, &[4, 5]);
+  fill_row_except(&mut board, (BOARD_HEIGHT - 2) as usize, &[4, 5]);
+  board.set_cell((BOARD_HEIGHT - 3) as usize, 0, Cell::Garbage);
+  board.queue_garbage(3, 0);
+  // a double sends 1 line, which eats one of the 3 coming in
+  board.apply(Action::HardDrop);
+  assert_eq!(board.pending_garbage_lines(), 2);
+  assert_eq!(board.take_attack(), 0);
+}
+
+#[test]
+fn restored_boards_play_out_the_same() {
+  let mut board = Board::with_seed(11);
+  let moves = [Action::MoveLeft, Action::RotateCw, Action::HardDrop, Action::Hold,
+               Action::MoveRight, Action::MoveRight, Action::HardDrop, Action::SoftDrop];
+  // get a few bags in so the rng has something to catch up on
+  for _ in 0..3 {
+    for &action in moves.iter() {
+      board.apply(action);
+    }
+  }
+  board.queue_garbage(2, 7);
+  board.update(0.3);
+
+  let mut copy = Board::from_snapshot(&board.snapshot());
+  assert_eq!(copy.snapshot(), board.snapshot());
+  assert_eq!(copy.ghost_piece, board.ghost_piece);
+  for _ in 0..5 {
+    for &action in moves.iter() {
+      board.apply(action);
+      copy.apply(action);
+    }
+    board.update(0.1);
+    copy.update(0.1);
+    assert_eq!(copy.snapshot(), board.snapshot());
+  }
+}
+
+#[test]
+fn reset_boards_start_over_like_new_ones() {
+  let mut board = Board::with_seed(3);
+  board.lock_delay = 1.0;
+  for _ in 0..30 {
+    board.apply(Action::HardDrop);
+  }
+  board.queue_garbage(4, 2);
+  board.state = GameState::Over;
+
+  board.reset(8);
+  assert_eq!(board.snapshot(), Board::with_seed(8).snapshot());
+  assert_eq!(board.state, GameState::Playing);
+  assert_eq!(board.pending_garbage_lines(), 0);
+  // the rules it was set up with stay
+  assert_eq!(board.lock_delay, 1.0);
+}
+
+#[test]
+fn paused_boards_stand_still() {
+  let mut board = Board::with_seed(5);
+  board.pause(true);
+  assert_eq!(board.state, GameState::Paused);
+  let before = board.snapshot();
+  board.update(10.0);
+  assert_eq!(board.snapshot(), before);
+
+  board.pause(false);
+  assert_eq!(board.state, GameState::Playing);
+  board.update(10.0);
+  assert!(board.snapshot() != before);
+
+  // or take any input, drops included
+  board.pause(true);
+  let before = board.snapshot();
+  for &action in [Action::MoveLeft, Action::RotateCw, Action::Hold, Action::SoftDrop, Action::HardDrop].iter() {
+    board.apply(action);
+  }
+  assert_eq!(board.snapshot(), before);
+  board.pause(false);
+
+  // there's no unpausing a board that's over
+  board.state = GameState::Over;
+  board.pause(true);
+  board.pause(false);
+  assert_eq!(board.state, GameState::Over);
+}
+
+#[test]
+fn last_board_standing_wins() {
+  let mut boards = vec![Board::with_seed(0), Board::with_seed(0), Board::with_seed(0)];
+  assert_eq!(board::winner(&boards), None);
+  assert_eq!(board::garbage_target(&boards, 2), Some(0));
+  boards[0].state = GameState::Over;
+  assert_eq!(board::winner(&boards), None);
+  assert_eq!(board::garbage_target(&boards, 2), Some(1));
+  boards[1].state = GameState::Over;
+  assert_eq!(board::winner(&boards), Some(2));
+  assert_eq!(board::garbage_target(&boards, 2), None);
+
+  // everybody topped out together
+  boards[2].state = GameState::Over;
+  boards[1].score = 500;
+  assert_eq!(board::winner(&boards), Some(1));
+}
@@ -0,0 +1,72 @@
+extern crate tetris;
+
+use tetris::config;
+use tetris::config::Config;
+
+fn args(args: &[&str]) -> Vec<String> {
+  args.iter().map(|a| a.to_string()).collect()
+}
+
+fn no_vars(_: &str) -> Option<String> {
+  None
+}
+
+#[test]
+fn defaults_with_nothing_set() {
+  let config = Config::parse(&[], no_vars).unwrap();
+  assert_eq!(config, Config::default());
+  assert_eq!(config.server, config::DEFAULT_SERVER);
+  assert_eq!(config.block_size, config::DEFAULT_BLOCK_SIZE);
+}
+
+fn some_vars(name: &str) -> Option<String> {
+  match name {
+    "TETRIS_SERVER" => Some("wss://tetris.example.com".to_string()),
+    "TETRIS_NAME" => Some("joe".to_string()),
+    "TETRIS_SCALE" => Some("40".to_string()),
+    "TETRIS_OFFLINE" => Some("1".to_string()),
+    _ => None
+  }
+}
+
+#[test]
+fn command_line_beats_the_environment() {
+  let config = Config::parse(&[], some_vars).unwrap();
+  assert_eq!(config.server, "wss://tetris.example.com");
+  assert_eq!(config.name, Some("joe".to_string()));
+  assert_eq!(config.block_size, 40);
+  assert!(config.offline);
+
+  let config = Config::parse(&args(&["--server", "10.0.0.2:3012", "--name", "alice", "--scale", "20",
+                                     "--font", "fonts/mono.ttf"]), some_vars).unwrap();
+  assert_eq!(config.server, "ws://10.0.0.2:3012");
+  assert_eq!(config.name, Some("alice".to_string()));
+  assert_eq!(config.block_size, 20);
+  assert_eq!(config.font, Some("fonts/mono.ttf".to_string()));
+
+  let config = Config::parse(&args(&["--replay", "game.replay", "--headless"]), no_vars).unwrap();
+  assert_eq!(config.replay, Some("game.replay".to_string()));
+  assert!(config.headless);
+}
+
+#[test]
+fn bad_options_are_errors() {
+  let bad = [
+    vec!["--server"],
+    vec!["--server", "http://localhost:3012"],
+    vec!["--scale", "big"],
+    vec!["--scale", "5"],
+    vec!["--scale", "61"],
+    vec!["--name", ""],
+    vec!["--name", "two words"],
+    vec!["--replay"],
+    vec!["--headless"],
+    vec!["--spectate", "--offline"],
+    vec!["--fullscreen"]
+  ];
+  for a in bad.iter() {
+    assert!(Config::parse(&args(a), no_vars).is_err(), "{:?} parsed", a);
+  }
+  let vars = |name: &str| if name == "TETRIS_SCALE" { Some("0".to_string()) } else { None };
+  assert!(Config::parse(&[], vars).is_err());
+}
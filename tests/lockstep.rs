@@ -0,0 +1,231 @@
+extern crate tetris;
+
+use tetris::board::{Action, Board, GameState};
+use tetris::lockstep::{Lockstep, INPUT_DELAY};
+
+// what each player presses, as (tick, action)
+fn player_inputs() -> Vec<Vec<(u64, Action)>> {
+  vec![
+    vec![(3, Action::MoveLeft), (3, Action::MoveLeft), (10, Action::HardDrop),
+         (40, Action::RotateCw), (41, Action::HardDrop), (90, Action::Hold), (95, Action::HardDrop)],
+    vec![(5, Action::MoveRight), (6, Action::RotateCcw), (7, Action::HardDrop),
+         (50, Action::SoftDrop), (51, Action::SoftDrop), (52, Action::HardDrop)],
+    vec![(4, Action::HardDrop), (20, Action::HardDrop), (30, Action::HardDrop), (44, Action::HardDrop)]
+  ]
+}
+
+#[test]
+fn clients_agree_whatever_order_inputs_arrive_in() {
+  let inputs = player_inputs();
+  let mut a = Lockstep::new(3, 99);
+  let mut b = Lockstep::new(3, 99);
+
+  // a gets everything player by player, b gets it tick by tick, backwards per tick
+  for (player, presses) in inputs.iter().enumerate() {
+    for &(tick, action) in presses.iter() {
+      a.add_input(player, tick, action).unwrap();
+    }
+  }
+  let mut all: Vec<(u64, usize, Action)> = Vec::new();
+  for (player, presses) in inputs.iter().enumerate() {
+    for &(tick, action) in presses.iter() {
+      all.push((tick, player, action));
+    }
+  }
+  all.sort_by(|x, y| x.0.cmp(&y.0).then(y.1.cmp(&x.1)));
+  for &(tick, player, action) in all.iter() {
+    b.add_input(player, tick, action).unwrap();
+  }
+
+  // a hears from everybody at once, b gets confirmations a bit at a time
+  for player in 0..3 {
+    a.confirm(player, 200);
+  }
+  assert_eq!(a.advance(), 200);
+  let mut tick = INPUT_DELAY;
+  while tick < 200 {
+    tick = (tick + 7).min(200);
+    for player in 0..3 {
+      b.confirm(player, tick);
+    }
+    b.advance();
+  }
+
+  assert_eq!(a.tick, b.tick);
+  for i in 0..3 {
+    assert_eq!(a.boards[i].snapshot(), b.boards[i].snapshot());
+  }
+  // nobody was standing still the whole time
+  assert!(a.boards.iter().all(|board| board.score > 0));
+}
+
+#[test]
+fn waits_for_the_slowest_player() {
+  let mut game = Lockstep::new(2, 1);
+  assert_eq!(game.advance(), INPUT_DELAY);
+  game.confirm(0, 50);
+  assert_eq!(game.advance(), 0);
+  game.confirm(1, 20);
+  assert_eq!(game.advance(), 20 - INPUT_DELAY);
+  assert_eq!(game.tick, 20);
+}
+
+#[test]
+fn inputs_for_confirmed_ticks_are_refused() {
+  let mut game = Lockstep::new(2, 1);
+  game.confirm(0, 10);
+  assert!(game.add_input(0, 9, Action::HardDrop).is_err());
+  assert!(game.add_input(0, 10, Action::HardDrop).is_ok());
+  // the other player hasn't confirmed anything yet
+  assert!(game.add_input(1, 9, Action::HardDrop).is_ok());
+}
+
+// a game with everybody's inputs in, confirmed up to `tick`
+fn played_to(inputs: &[Vec<(u64, Action)>], tick: u64) -> Lockstep {
+  let mut game = Lockstep::new(inputs.len(), 99);
+  for (player, presses) in inputs.iter().enumerate() {
+    for &(t, action) in presses.iter() {
+      game.add_input(player, t, action).unwrap();
+    }
+    game.confirm(player, tick);
+  }
+  game.advance();
+  game
+}
+
+#[test]
+fn drifting_boards_are_caught_and_resynced() {
+  let inputs = player_inputs();
+  let good = played_to(&inputs, 200);
+  let early = played_to(&inputs, 60);
+  let snapshot = early.boards[1].snapshot();
+
+  // a press that never happened anywhere else
+  let mut wrong = inputs.clone();
+  wrong[1].push((20, Action::MoveLeft));
+  let mut bad = played_to(&wrong, 200);
+  assert_eq!(bad.checksum(0, 120), good.checksum(0, 120));
+  assert!(bad.checksum(1, 120) != good.checksum(1, 120));
+
+  for player in 0..3 {
+    bad.report_checksum(player, 120, good.checksum(player, 120).unwrap());
+  }
+  assert_eq!(bad.take_desyncs(), vec![1]);
+  assert_eq!(bad.take_desyncs(), vec![]);
+
+  // the owner's board from tick 60, played forward with what's happened since
+  bad.resync(1, 60, &snapshot).unwrap();
+  assert_eq!(bad.boards[1].snapshot(), good.boards[1].snapshot());
+  assert_eq!(bad.boards[1].checksum(), good.boards[1].checksum());
+
+  // and one from a tick we haven't got to yet waits for it
+  let mut ahead = played_to(&wrong, 40);
+  ahead.resync(1, 60, &snapshot).unwrap();
+  assert!(ahead.boards[1].snapshot() != snapshot);
+  for player in 0..3 {
+    ahead.confirm(player, 200);
+  }
+  ahead.advance();
+  assert_eq!(ahead.boards[1].snapshot(), good.boards[1].snapshot());
+
+  // more than one can wait at once, whatever order they turn up in
+  let later = played_to(&inputs, 100).boards[1].snapshot();
+  wrong[1].push((80, Action::MoveRight));
+  let mut ahead = played_to(&wrong, 40);
+  ahead.resync(1, 100, &later).unwrap();
+  ahead.resync(1, 60, &snapshot).unwrap();
+  for &tick in [70, 200].iter() {
+    for player in 0..3 {
+      ahead.confirm(player, tick);
+    }
+    ahead.advance();
+    assert_eq!(ahead.boards[1].snapshot(), played_to(&inputs, tick).boards[1].snapshot(), "tick {}", tick);
+  }
+}
+
+#[test]
+fn checksums_reported_early_are_checked_later() {
+  let inputs = player_inputs();
+  let good = played_to(&inputs, 200);
+  let mut wrong = inputs.clone();
+  wrong[2].push((10, Action::Hold));
+  let mut bad = played_to(&wrong, 100);
+  bad.report_checksum(2, 180, good.checksum(2, 180).unwrap());
+  assert_eq!(bad.take_desyncs(), vec![]);
+  for player in 0..3 {
+    bad.confirm(player, 200);
+  }
+  bad.advance();
+  assert_eq!(bad.take_desyncs(), vec![2]);
+}
+
+#[test]
+fn nobody_waits_for_players_who_left() {
+  let mut game = Lockstep::new(3, 1);
+  game.confirm(0, 100);
+  game.confirm(1, 100);
+  game.confirm(2, 30);
+  assert_eq!(game.advance(), 30);
+
+  // they're gone from tick 30 on, whatever they'd have pressed after that
+  game.remove_player(2, 30);
+  assert_eq!(game.advance(), 70);
+  assert_eq!(game.boards[2].state, GameState::Over);
+  assert_eq!(game.boards[0].state, GameState::Playing);
+}
+
+#[test]
+fn new_rounds_start_fresh_without_players_who_left() {
+  let mut game = Lockstep::new(3, 1);
+  game.add_input(0, 5, Action::HardDrop).unwrap();
+  game.remove_player(2, 20);
+  for player in 0..3 {
+    game.confirm(player, 100);
+  }
+  game.advance();
+
+  game.reset(2);
+  assert_eq!(game.tick, 0);
+  for i in 0..2 {
+    assert_eq!(game.boards[i].snapshot(), Board::with_seed(2).snapshot());
+  }
+  // player 3 is out from the start, and nobody waits for them
+  game.confirm(0, 50);
+  game.confirm(1, 50);
+  assert_eq!(game.advance(), 50);
+  assert_eq!(game.boards[2].state, GameState::Over);
+  assert_eq!(game.boards[0].state, GameState::Playing);
+}
+
+#[test]
+fn pausing_stops_every_board_on_the_same_tick() {
+  let mut game = Lockstep::new(2, 4);
+  game.pause(1, 30, true).unwrap();
+  assert!(game.paused());
+  game.pause(0, 90, false).unwrap();
+  assert!(!game.paused());
+  // inputs while paused don't do anything
+  game.add_input(0, 50, Action::HardDrop).unwrap();
+
+  let mut still = Lockstep::new(2, 4);
+  game.confirm(0, 60);
+  game.confirm(1, 60);
+  still.confirm(0, 30);
+  still.confirm(1, 30);
+  game.advance();
+  still.advance();
+  assert!(game.boards.iter().all(|b| b.state == GameState::Paused));
+  assert_eq!(game.resuming_in(), Some(30));
+  for i in 0..2 {
+    assert_eq!(game.boards[i].snapshot().cells, still.boards[i].snapshot().cells);
+    assert_eq!(game.boards[i].score, still.boards[i].score);
+  }
+
+  game.confirm(0, 200);
+  game.confirm(1, 200);
+  game.advance();
+  assert!(game.boards.iter().all(|b| b.state == GameState::Playing));
+  assert_eq!(game.resuming_in(), None);
+  // a pause has to come before the player confirms its tick, like any input
+  assert!(game.pause(0, 150, true).is_err());
+}
@@ -0,0 +1,279 @@
+extern crate tetris;
+
+use tetris::block::Shape;
+use tetris::board::{Action, Board, Cell, Clear, Spin};
+use tetris::protocol;
+use tetris::protocol::{ClientMessage, Frame, LobbyPlayer, ServerMessage};
+
+fn lobby() -> Vec<LobbyPlayer> {
+  vec![
+    LobbyPlayer { token: 1, name: "alice".to_string(), ready: true },
+    LobbyPlayer { token: 3, name: "Player3".to_string(), ready: false }
+  ]
+}
+
+#[test]
+fn messages_survive_a_round_trip() {
+  let client = vec![
+    ClientMessage::Ack { token: 2, version: 0, name: None },
+    ClientMessage::Ack { token: 2, version: 1, name: None },
+    ClientMessage::Ack { token: 2, version: 1, name: Some("alice".to_string()) },
+    ClientMessage::Input(1, 0, Action::MoveLeft),
+    ClientMessage::Input(1, 77, Action::RotateCcw),
+    ClientMessage::Input(3, 12, Action::Hold),
+    ClientMessage::Confirm(2, 300),
+    ClientMessage::Garbage { from: 1, to: 2, lines: 4, hole: 9 },
+    ClientMessage::GameOver(2),
+    ClientMessage::Checksum(2, 120, 0x0123_4567_89ab_cdef),
+    ClientMessage::Desync(1, 3),
+    ClientMessage::Resync(3, 240, Box::new(Board::with_seed(9).snapshot())),
+    ClientMessage::Rejoin { token: 2, version: 1 },
+    ClientMessage::Ready(2, true),
+    ClientMessage::Ready(3, false),
+    ClientMessage::Pause(1, 240, true),
+    ClientMessage::Pause(2, 420, false)
+  ];
+  for message in client {
+    assert_eq!(ClientMessage::parse(&message.to_string()), Ok(message.clone()));
+    assert_eq!(ServerMessage::parse(&message.to_string()), Ok(ServerMessage::Relay(message)));
+  }
+
+  let server = vec![
+    ServerMessage::NumConns { num_players: 4, token: 3 },
+    ServerMessage::Start { seed: Some(12345), authoritative: false },
+    ServerMessage::Start { seed: Some(12345), authoritative: true },
+    ServerMessage::Start { seed: None, authoritative: false },
+    ServerMessage::Version(1),
+    ServerMessage::Left(2, 600),
+    ServerMessage::Lobby(vec![]),
+    ServerMessage::Lobby(lobby()),
+    ServerMessage::Countdown(3),
+    ServerMessage::Spectating(4),
+    ServerMessage::RoundOver { winner: 2, match_over: false, wins: vec![1, 1, 0] },
+    ServerMessage::RoundOver { winner: 1, match_over: true, wins: vec![2, 1] }
+  ];
+  for message in server {
+    assert_eq!(ServerMessage::parse(&message.to_string()), Ok(message.clone()));
+  }
+}
+
+#[test]
+fn matches_the_old_wire_format() {
+  assert_eq!(ClientMessage::LegacyInput(2, Action::HardDrop).to_string(), "KEYSTROKE 2 SPACE");
+  assert_eq!(ClientMessage::LegacyInput(2, Action::Hold).to_string(), "HOLD 2");
+  assert_eq!(ClientMessage::parse("KEYSTROKE 1 LEFT"), Ok(ClientMessage::LegacyInput(1, Action::MoveLeft)));
+  assert_eq!(ClientMessage::parse("HOLD 2"), Ok(ClientMessage::LegacyInput(2, Action::Hold)));
+  assert_eq!(ClientMessage::parse("FIRST_BLOCK 1 I"), Ok(ClientMessage::FirstBlock(1, Shape::I)));
+  assert_eq!(ClientMessage::parse("NEW_BLOCK 2 T"), Ok(ClientMessage::NewBlock(2, Shape::T)));
+  assert_eq!(ClientMessage::NewBlock(2, Shape::T).to_string(), "NEW_BLOCK 2 T");
+  // ticks go on the end, where old clients won't look for them
+  assert_eq!(ClientMessage::Input(2, 9, Action::HardDrop).to_string(), "KEYSTROKE 2 SPACE 9");
+  assert_eq!(ClientMessage::Input(2, 9, Action::Hold).to_string(), "HOLD 2 9");
+  assert_eq!(ServerMessage::parse("NUM_CONNS 2 1"), Ok(ServerMessage::NumConns { num_players: 2, token: 1 }));
+  // acks from clients that don't know about versions
+  assert_eq!(ClientMessage::parse("CLIENT_ACK 1"), Ok(ClientMessage::Ack { token: 1, version: 0, name: None }));
+  assert_eq!(ClientMessage::Ack { token: 1, version: 0, name: None }.to_string(), "CLIENT_ACK 1");
+}
+
+#[test]
+fn binary_messages_survive_a_round_trip() {
+  let client = vec![
+    ClientMessage::Ack { token: 2, version: 1, name: None },
+    ClientMessage::Ack { token: 2, version: 1, name: Some("jöe".to_string()) },
+    ClientMessage::Input(1, 1 << 40, Action::SoftDrop),
+    ClientMessage::Input(8, 5, Action::Hold),
+    ClientMessage::Confirm(4, 1000),
+    ClientMessage::Garbage { from: 1, to: 2, lines: 4, hole: 9 },
+    ClientMessage::GameOver(2),
+    ClientMessage::Checksum(2, 120, 0xffff_0000_ffff_0000),
+    ClientMessage::Desync(1, 3),
+    ClientMessage::Resync(3, 240, Box::new(Board::with_seed(9).snapshot())),
+    ClientMessage::Rejoin { token: 2, version: 1 },
+    ClientMessage::Ready(2, true),
+    ClientMessage::Ready(3, false),
+    ClientMessage::Pause(1, 240, true),
+    ClientMessage::Pause(2, 420, false)
+  ];
+  for message in client {
+    let frame = message.encode(protocol::PROTOCOL_VERSION);
+    match frame {
+      Frame::Binary(_) => (),
+      Frame::Text(_) => panic!("{:?} was encoded as text", message)
+    }
+    assert_eq!(ClientMessage::decode(&frame), Ok(message.clone()));
+    assert_eq!(ServerMessage::decode(&frame), Ok(ServerMessage::Relay(message)));
+  }
+
+  let server = vec![
+    ServerMessage::NumConns { num_players: 4, token: 3 },
+    ServerMessage::Start { seed: Some(0xdead_beef_cafe_f00d), authoritative: true },
+    ServerMessage::Start { seed: None, authoritative: false },
+    ServerMessage::Version(1),
+    ServerMessage::Left(2, 600),
+    ServerMessage::Lobby(vec![]),
+    ServerMessage::Lobby(lobby()),
+    ServerMessage::Countdown(3),
+    ServerMessage::Spectating(4),
+    ServerMessage::RoundOver { winner: 2, match_over: false, wins: vec![1, 1, 0] },
+    ServerMessage::RoundOver { winner: 1, match_over: true, wins: vec![2, 1] }
+  ];
+  for message in server {
+    assert_eq!(ServerMessage::decode(&message.encode(protocol::PROTOCOL_VERSION)), Ok(message.clone()));
+  }
+
+  // v0 is still plain text
+  let legacy = ClientMessage::LegacyInput(1, Action::MoveLeft).encode(protocol::LEGACY_VERSION);
+  assert_eq!(legacy, Frame::Text("KEYSTROKE 1 LEFT".to_string()));
+  let legacy = ClientMessage::NewBlock(1, Shape::Z).encode(protocol::PROTOCOL_VERSION);
+  assert_eq!(legacy, Frame::Text("NEW_BLOCK 1 Z".to_string()));
+  let legacy = ClientMessage::Input(1, 30, Action::MoveLeft).encode(protocol::LEGACY_VERSION);
+  assert_eq!(legacy, Frame::Text("KEYSTROKE 1 LEFT 30".to_string()));
+}
+
+#[test]
+fn bad_binary_messages_are_errors() {
+  let garbage = ClientMessage::Garbage { from: 1, to: 2, lines: 4, hole: 9 };
+  let good = match garbage.encode(protocol::PROTOCOL_VERSION) {
+    Frame::Binary(bytes) => bytes,
+    Frame::Text(_) => panic!("encoded as text")
+  };
+
+  let mut bad = vec![
+    vec![],
+    vec![1],
+    good[..good.len() - 1].to_vec(),
+    // unknown tag
+    vec![1, 99, 0, 0, 0, 1]
+  ];
+  // trailing junk
+  let mut long = good.clone();
+  long.push(0);
+  bad.push(long);
+  // version we don't speak, and v0 isn't binary
+  let mut future = good.clone();
+  future[0] = protocol::PROTOCOL_VERSION + 1;
+  bad.push(future);
+  let mut legacy = good.clone();
+  legacy[0] = protocol::LEGACY_VERSION;
+  bad.push(legacy);
+  // hole off the board
+  let mut hole = good.clone();
+  *hole.last_mut().unwrap() = 200;
+  bad.push(hole);
+
+  for bytes in bad {
+    assert!(ClientMessage::decode(&Frame::Binary(bytes.clone())).is_err(), "{:?} decoded", bytes);
+  }
+  assert!(ClientMessage::decode(&Frame::Binary(good)).is_ok());
+}
+
+#[test]
+fn bad_messages_are_errors() {
+  let bad = [
+    "",
+    "   ",
+    "WHAT 1",
+    "KEYSTROKE",
+    "KEYSTROKE one LEFT",
+    "KEYSTROKE 1 SIDEWAYS 0",
+    "KEYSTROKE 1 LEFT -1",
+    "HOLD 1 soon",
+    "CONFIRM 1",
+    "HOLD",
+    "NEW_BLOCK 1",
+    "NEW_BLOCK 1 Q",
+    "FIRST_BLOCK one T",
+    "GARBAGE 1 2 3",
+    "GARBAGE 1 2 -3 0",
+    "GARBAGE 1 2 3 10",
+    "GARBAGE 1 2 1000 0",
+    "GAME_OVER 99999999999",
+    "CHECKSUM 1 60",
+    "DESYNC 1",
+    "RESYNC 1 60",
+    "REJOIN 1",
+    "CLIENT_ACK 1 1 two words",
+    "CLIENT_ACK 1 1 seventeen_letters",
+    "READY 1",
+    "READY 1 2",
+    "PAUSE 1 60"
+  ];
+  for text in bad.iter() {
+    assert!(ClientMessage::parse(text).is_err(), "{:?} parsed", text);
+  }
+  assert!(ServerMessage::parse("NUM_CONNS 2 3").is_err());
+  assert!(ServerMessage::parse("NUM_CONNS 2 0").is_err());
+  assert!(ServerMessage::parse("START! soon").is_err());
+  assert!(ServerMessage::parse("LEFT 2").is_err());
+  assert!(ServerMessage::parse("LOBBY 2 1 alice 1").is_err());
+  assert!(ServerMessage::parse("LOBBY 1 1 alice 1 extra").is_err());
+  assert!(ServerMessage::parse("SPECTATING 0").is_err());
+  assert!(ServerMessage::parse("ROUND_OVER 3 0 2 1 0").is_err());
+  assert!(ServerMessage::parse("ROUND_OVER 1 0 2 1").is_err());
+}
+
+#[test]
+fn board_index_checks_the_token() {
+  assert_eq!(protocol::board_index(1, 2), Ok(0));
+  assert_eq!(protocol::board_index(2, 2), Ok(1));
+  assert!(protocol::board_index(0, 2).is_err());
+  assert!(protocol::board_index(3, 2).is_err());
+  assert!(protocol::board_index(-1, 2).is_err());
+}
+
+#[test]
+fn board_state_survives_both_encodings() {
+  let mut board = Board::with_seed(4);
+  board.set_cell(21, 0, Cell::Garbage);
+  board.apply(Action::RotateCw);
+  board.apply(Action::HardDrop);
+  board.apply(Action::Hold);
+  board.apply(Action::MoveLeft);
+  board.apply(Action::RotateCcw);
+  board.queue_garbage(3, 2);
+  board.update(0.25);
+  board.back_to_back = true;
+  board.last_clear = Some(Clear {
+    lines: 2,
+    spin: Spin::Mini,
+    all_clear: false,
+    back_to_back: true,
+    combo: 3,
+    points: 900
+  });
+
+  let message = ServerMessage::State(2, Box::new(board.snapshot()));
+  assert_eq!(ServerMessage::parse(&message.to_string()), Ok(message.clone()));
+  for &version in [protocol::LEGACY_VERSION, protocol::PROTOCOL_VERSION].iter() {
+    assert_eq!(ServerMessage::decode(&message.encode(version)), Ok(message.clone()));
+  }
+}
+
+#[test]
+fn bad_board_state_is_an_error() {
+  let text = ServerMessage::State(1, Box::new(Board::with_seed(4).snapshot())).to_string();
+  // first cell
+  let mut fields: Vec<&str> = text.split_whitespace().collect();
+  fields[2] = "99";
+  assert!(ServerMessage::parse(&fields.join(" ")).is_err());
+  // cut off
+  let fields: Vec<&str> = text.split_whitespace().collect();
+  assert!(ServerMessage::parse(&fields[..fields.len() - 1].join(" ")).is_err());
+  assert!(ServerMessage::parse(&format!("{} 0", text)).is_err());
+
+  // numbers that would hang or overflow whoever restores the board
+  let good = Board::with_seed(4).snapshot();
+  let mut bad = vec![good.clone(); 7];
+  bad[0].bags_drawn = 4_000_000_000;
+  bad[1].score = u64::MAX;
+  bad[2].level = 1000;
+  bad[3].combo = 5;
+  bad[4].gravity_timer = f64::NAN;
+  bad[5].lock_timer = -1.0;
+  bad[6].pending_garbage = vec![(u32::MAX, 0)];
+  for snapshot in bad {
+    let message = ServerMessage::State(1, Box::new(snapshot));
+    assert!(ServerMessage::parse(&message.to_string()).is_err(), "{:?} parsed", message);
+    assert!(ServerMessage::decode(&message.encode(protocol::PROTOCOL_VERSION)).is_err(), "{:?} decoded", message);
+  }
+}
@@ -0,0 +1,115 @@
+extern crate tetris;
+
+use tetris::TICKS_PER_SECOND;
+use tetris::board::Action;
+use tetris::lockstep::Lockstep;
+use tetris::replay;
+use tetris::replay::{Event, Playback, Replay};
+
+// games recorded with an earlier build. if one of these stops matching, something
+// changed how the boards play
+const FIXTURES: [(&str, &str); 2] = [
+  ("solo", include_str!("replays/solo.replay")),
+  ("versus", include_str!("replays/versus.replay"))
+];
+
+fn played_to(tick: u64, inputs: &[(usize, u64, Action)]) -> Lockstep {
+  let mut game = Lockstep::new(2, 42);
+  for &(player, t, action) in inputs.iter() {
+    game.add_input(player, t, action).unwrap();
+  }
+  game.remove_player(1, 150);
+  game.pause(0, 100, true).unwrap();
+  game.pause(1, 130, false).unwrap();
+  game.confirm(0, tick);
+  game.confirm(1, tick);
+  game.advance();
+  game
+}
+
+#[test]
+fn recorded_fixtures_still_play_out_the_same() {
+  for &(name, text) in FIXTURES.iter() {
+    let replay = Replay::parse(text).unwrap();
+    let game = replay::run(&replay).unwrap();
+    assert_eq!(game.tick, replay.length, "{}", name);
+    assert!(replay.check(&game).is_ok(), "{}: {:?}", name, replay.check(&game));
+    // and they come back out the way they went in
+    assert_eq!(format!("{}", replay), text, "{}", name);
+  }
+}
+
+#[test]
+fn a_recorded_game_plays_back_the_same() {
+  let inputs = [(0, 3, Action::MoveLeft), (1, 5, Action::HardDrop), (0, 20, Action::Hold),
+                (0, 21, Action::HardDrop), (1, 60, Action::RotateCcw), (1, 200, Action::HardDrop)];
+  let game = played_to(300, &inputs);
+  let recorded = game.replay();
+  assert_eq!(recorded.length, 300);
+  // player 2 was gone by tick 200, so that one never happened
+  assert_eq!(recorded.events.len(), 8);
+  assert!(recorded.events.contains(&(150, Event::Left(1))));
+  assert!(recorded.events.contains(&(100, Event::Pause(0, true))));
+
+  let replay = Replay::parse(&format!("{}", recorded)).unwrap();
+  assert_eq!(replay, recorded);
+  let played = replay::run(&replay).unwrap();
+  assert!(replay.check(&played).is_ok());
+  for i in 0..2 {
+    assert_eq!(played.boards[i].snapshot(), game.boards[i].snapshot());
+  }
+}
+
+#[test]
+fn seeking_lands_on_the_same_boards_either_way() {
+  let replay = Replay::parse(FIXTURES[1].1).unwrap();
+  let mut playback = Playback::new(replay.clone());
+  let mut game = playback.start().unwrap();
+
+  // half a second at a time at double speed, then back to the start and forward again
+  playback.set_speed(2.0);
+  for _ in 0..10 {
+    playback.update(&mut game, 0.5).unwrap();
+  }
+  assert_eq!(game.tick, 10 * TICKS_PER_SECOND);
+  let there = game.boards[0].snapshot();
+  playback.seek(&mut game, 0).unwrap();
+  assert_eq!(playback.tick(), 0);
+  playback.seek(&mut game, 10 * TICKS_PER_SECOND).unwrap();
+  assert_eq!(game.boards[0].snapshot(), there);
+
+  // nothing happens while paused, and it stops at the end
+  playback.paused = true;
+  playback.update(&mut game, 10.0).unwrap();
+  assert_eq!(playback.tick(), 10 * TICKS_PER_SECOND);
+  playback.paused = false;
+  playback.set_speed(100.0);
+  assert_eq!(playback.speed, replay::MAX_SPEED);
+  playback.update(&mut game, 1000.0).unwrap();
+  assert!(playback.finished());
+  assert_eq!(game.tick, replay.length);
+}
+
+#[test]
+fn bad_replays_are_errors() {
+  let bad = [
+    "",
+    "REPLAY 2 1 1\nEND 10",
+    "REPLAY 1 1 0\nEND 10",
+    "REPLAY 1 1 9\nEND 10",
+    "REPLAY 1 1 4000000000\nEND 10",
+    "REPLAY 1 1 1\nKEYSTROKE 1 LEFT 5",
+    "REPLAY 1 1 1\nKEYSTROKE 2 LEFT 5\nEND 10",
+    "REPLAY 1 1 1\nKEYSTROKE 1 LEFT 1\nEND 10",
+    "REPLAY 1 1 2\nNAME 1 two words\nEND 10",
+    "REPLAY 1 1 1\nCONFIRM 1 5\nEND 10",
+    "REPLAY 1 1 2\nEND 10\nBOARD 1 0 0"
+  ];
+  for text in bad.iter() {
+    assert!(Replay::parse(text).is_err(), "{:?} parsed", text);
+  }
+  // a board that doesn't end up where it should
+  let mut replay = Replay::parse(FIXTURES[0].1).unwrap();
+  replay.results[0].0 += 1;
+  assert!(replay.check(&replay::run(&replay).unwrap()).is_err());
+}